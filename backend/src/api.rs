@@ -0,0 +1,579 @@
+use crate::config::{ServerConfig, UserProfile};
+use crate::exec::{self, Captured, ExitReport};
+use crate::limits::{self, CgroupEvents, SessionCgroup};
+use crate::lobby::Lobby;
+use crate::invite::{InviteRole, DEFAULT_INVITE_SECS};
+use crate::message::{
+    ExecCgroup, ExecFinished, ExecList, ExecStarted, InviteCreate, InviteList, InviteRevoke, JobLogFiles, OutputEncoding, RoomClose,
+    RoomCreate, RoomInspect, RoomList, SessionDisconnect, SessionInspect, SessionKill, SessionList,
+};
+use crate::metrics::METRICS;
+use crate::room::{NewRoom, RoomError};
+use crate::shutdown::Draining;
+use actix::Addr;
+use actix_web::web::Bytes;
+use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::Duration;
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct TokenParams {
+    token: Option<String>,
+}
+
+/// The signed-in caller, or `None` when the server runs without users
+fn caller<'a>(config: &'a ServerConfig, params: &TokenParams) -> Result<Option<&'a UserProfile>, Error> {
+    config
+        .authenticate(params.token.as_deref())
+        .map_err(|e| {
+            METRICS.auth_failed("api");
+            actix_web::error::ErrorUnauthorized(e)
+        })
+}
+
+fn is_admin(config: &ServerConfig, user: Option<&UserProfile>) -> bool {
+    config.role(user.map_or(&config.anonymous_role, |u| &u.role)).admin
+}
+
+pub fn room_error(e: RoomError) -> Error {
+    match e {
+        RoomError::NotFound => actix_web::error::ErrorNotFound(e.to_string()),
+        RoomError::Forbidden(_) => actix_web::error::ErrorForbidden(e.to_string()),
+        RoomError::Conflict(_) => actix_web::error::ErrorConflict(e.to_string()),
+        RoomError::Invalid(_) => actix_web::error::ErrorBadRequest(e.to_string()),
+    }
+}
+
+#[post("/api/rooms")]
+pub async fn create_room(
+    body: web::Json<NewRoom>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let user = caller(&config, &params)?;
+    let room = lobby
+        .send(RoomCreate { room: body.into_inner(), created_by: user.map(|u| u.name.clone()) })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(room_error)?;
+    Ok(HttpResponse::Created().json(room))
+}
+
+#[get("/api/rooms")]
+pub async fn list_rooms(
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    caller(&config, &params)?;
+    let rooms = lobby.send(RoomList).await.map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "rooms": rooms })))
+}
+
+/// A room's settings and who is in it, by id or slug
+#[get("/api/rooms/{room}")]
+pub async fn inspect_room(
+    path: web::Path<String>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let user = caller(&config, &params)?;
+    let room = lobby
+        .send(RoomInspect {
+            room: path.into_inner(),
+            user: user.map(|u| u.name.clone()),
+            admin: is_admin(&config, user),
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(room_error)?;
+    Ok(HttpResponse::Ok().json(room))
+}
+
+/// Close a room, disconnecting its members
+#[delete("/api/rooms/{room}")]
+pub async fn close_room(
+    path: web::Path<String>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let user = caller(&config, &params)?;
+    lobby
+        .send(RoomClose {
+            room: path.into_inner(),
+            user: user.map(|u| u.name.clone()),
+            admin: is_admin(&config, user),
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(room_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NewInvite {
+    role: InviteRole,
+    expires_in_secs: u64,
+}
+
+impl Default for NewInvite {
+    fn default() -> Self {
+        NewInvite { role: InviteRole::Viewer, expires_in_secs: DEFAULT_INVITE_SECS }
+    }
+}
+
+/// Mint a signed invite link into a room, e.g. read-only for 30 minutes
+#[post("/api/rooms/{room}/invites")]
+pub async fn create_invite(
+    path: web::Path<String>,
+    body: web::Json<NewInvite>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let user = caller(&config, &params)?;
+    let invite = lobby
+        .send(InviteCreate {
+            room: path.into_inner(),
+            role: body.role,
+            expires_in_secs: body.expires_in_secs,
+            user: user.map(|u| u.name.clone()),
+            admin: is_admin(&config, user),
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(room_error)?;
+    Ok(HttpResponse::Created().json(invite))
+}
+
+fn require_admin(config: &ServerConfig, params: &TokenParams) -> Result<(), Error> {
+    if is_admin(config, caller(config, params)?) {
+        Ok(())
+    } else {
+        Err(actix_web::error::ErrorForbidden("admins only"))
+    }
+}
+
+#[get("/api/invites")]
+pub async fn list_invites(
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&config, &params)?;
+    let invites = lobby.send(InviteList).await.map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "invites": invites })))
+}
+
+/// Revoke an invite; sessions that joined with it are disconnected
+#[delete("/api/invites/{invite_id}")]
+pub async fn revoke_invite(
+    path: web::Path<Uuid>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&config, &params)?;
+    lobby
+        .send(InviteRevoke { invite_id: path.into_inner() })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(room_error)?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[get("/api/admin/sessions")]
+pub async fn list_sessions(
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&config, &params)?;
+    let sessions = lobby.send(SessionList).await.map_err(actix_web::error::ErrorInternalServerError)?;
+    let execs = lobby.send(ExecList).await.map_err(actix_web::error::ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "sessions": sessions, "execs": execs })))
+}
+
+#[get("/api/admin/sessions/{session_id}")]
+pub async fn inspect_session(
+    path: web::Path<Uuid>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&config, &params)?;
+    let session = path.into_inner();
+    let details = lobby
+        .send(SessionInspect { session })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound(format!("no session {}", session)))?;
+    Ok(HttpResponse::Ok().json(details))
+}
+
+/// Force a session off the server
+#[delete("/api/admin/sessions/{session_id}")]
+pub async fn disconnect_session(
+    path: web::Path<Uuid>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    require_admin(&config, &params)?;
+    let session = path.into_inner();
+    let found = lobby
+        .send(SessionDisconnect { session, reason: "disconnected by an admin".to_string() })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if !found {
+        return Err(actix_web::error::ErrorNotFound(format!("no session {}", session)));
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Deserialize)]
+pub struct KillParams {
+    token: Option<String>,
+    signal: Option<String>,
+}
+
+/// Signal every process a session is running, leaving the session connected
+#[post("/api/admin/sessions/{session_id}/kill")]
+pub async fn kill_session_processes(
+    path: web::Path<Uuid>,
+    params: web::Query<KillParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let params = params.into_inner();
+    require_admin(&config, &TokenParams { token: params.token })?;
+    let session = path.into_inner();
+    let signalled = lobby
+        .send(SessionKill { session, signal: params.signal })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound(format!("no session {}", session)))?
+        .map_err(actix_web::error::ErrorBadRequest)?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "sessionId": session, "signalled": signalled })))
+}
+
+/// Download the full spooled log of one of the caller's detached jobs
+#[get("/api/jobs/{job_id}/log")]
+pub async fn job_log(
+    path: web::Path<u32>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+) -> Result<HttpResponse, Error> {
+    let job_id = path.into_inner();
+    let user = caller(&config, &params)?
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("job logs need a signed-in user"))?;
+
+    let files = lobby
+        .send(JobLogFiles { job_id, user: user.name.clone() })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .ok_or_else(|| actix_web::error::ErrorNotFound(format!("no detached job {}", job_id)))?;
+
+    let log = web::block(move || {
+        let mut log = Vec::new();
+        for file in files {
+            // A file may rotate away while we read; what's left is still in order
+            if let Ok(bytes) = std::fs::read(file) {
+                log.extend_from_slice(&bytes);
+            }
+        }
+        log
+    })
+    .await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; charset=utf-8")
+        .insert_header(("Content-Disposition", format!("attachment; filename=\"job-{}.log\"", job_id)))
+        .body(log))
+}
+
+/// A one-shot command for `POST /api/exec`, run outside any session
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecRequest {
+    command: String,
+    shell: Option<String>,
+    /// Starting directory; defaults to the user's `cwd`, then the server's
+    cwd: Option<PathBuf>,
+    /// Added on top of the user's profile environment
+    #[serde(default)]
+    env: HashMap<String, String>,
+    /// Fed to the command, which otherwise gets an empty stdin
+    stdin: Option<String>,
+    timeout_secs: Option<u64>,
+    max_output_bytes: Option<u64>,
+    /// `text` or `base64`
+    #[serde(default)]
+    output_encoding: OutputEncoding,
+}
+
+fn encode_output(bytes: &[u8], encoding: OutputEncoding) -> String {
+    match encoding {
+        OutputEncoding::Base64 => BASE64.encode(bytes),
+        _ => String::from_utf8_lossy(bytes).to_string(),
+    }
+}
+
+/// How often a quiet `POST /api/exec` stream is written to, to notice a client that left
+const SSE_KEEPALIVE: Duration = Duration::from_secs(5);
+
+fn sse_frame(event: &str, data: &serde_json::Value) -> Bytes {
+    Bytes::from(format!("event: {}\ndata: {}\n\n", event, data))
+}
+
+/// Read a finished command's cgroup counters, then remove the cgroup. Blocks briefly.
+fn release_cgroup(cgroup: Option<SessionCgroup>) -> CgroupEvents {
+    let Some(cgroup) = cgroup else {
+        return CgroupEvents::default();
+    };
+    let events = cgroup.events();
+    cgroup.remove();
+    events
+}
+
+/// How a one-shot command went, shaped like a `command_output` payload.
+/// `events` are the cgroup's counters from before and after the command.
+fn exec_result(
+    exec_id: Uuid,
+    command: &str,
+    cwd: &Path,
+    captured: Captured,
+    encoding: OutputEncoding,
+    timeout: Duration,
+    events: (CgroupEvents, CgroupEvents),
+) -> serde_json::Value {
+    let exit = ExitReport::from_captured(&captured);
+    METRICS.record_exit("exec", &exit);
+
+    let mut notes = Vec::new();
+    let mut limit_exceeded = None;
+    match &captured.status {
+        Ok(_) if captured.timed_out => notes.push(format!("Command timed out after {} seconds", timeout.as_secs())),
+        Ok(status) => limit_exceeded = limits::limit_hit(status, &events.0, &events.1),
+        Err(e) => notes.push(format!("Process wait error: {}", e)),
+    }
+    if let Some(limit) = limit_exceeded {
+        notes.push(format!("Command stopped: {} limit exceeded", limit));
+    }
+    if captured.truncated {
+        notes.push(format!("Output truncated: command wrote {} bytes", captured.stdout_bytes + captured.stderr_bytes));
+    }
+
+    serde_json::json!({
+        "execId": exec_id,
+        "command": command,
+        "currentDirectory": cwd,
+        "exitCode": exit.code.unwrap_or(-1),
+        "exit": exit.to_json(),
+        "limitExceeded": limit_exceeded,
+        "encoding": encoding,
+        "stdout": encode_output(&captured.stdout, encoding),
+        "stderr": encode_output(&captured.stderr, encoding),
+        "truncated": captured.truncated,
+        "stdoutBytes": captured.stdout_bytes,
+        "stderrBytes": captured.stderr_bytes,
+        "notes": notes
+    })
+}
+
+/// Run one command and return its result. With `Accept: text/event-stream`,
+/// output streams as `stdout`/`stderr` events and the result follows as `exit`.
+#[post("/api/exec")]
+pub async fn exec_command(
+    req: HttpRequest,
+    body: web::Json<ExecRequest>,
+    params: web::Query<TokenParams>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+    draining: web::Data<Draining>,
+) -> Result<HttpResponse, Error> {
+    if draining.is_draining() {
+        return Err(actix_web::error::ErrorServiceUnavailable("server is shutting down"));
+    }
+    let user = caller(&config, &params)?;
+    let request = body.into_inner();
+    if request.command.trim().is_empty() {
+        return Err(actix_web::error::ErrorBadRequest("command must not be empty"));
+    }
+    if request.output_encoding == OutputEncoding::Binary {
+        return Err(actix_web::error::ErrorBadRequest("binary output needs a WebSocket; use base64"));
+    }
+
+    let shell_name = request
+        .shell
+        .clone()
+        .or_else(|| user.and_then(|u| u.shell.clone()))
+        .unwrap_or_else(|| config.default_shell.clone());
+    let shell = config.shell(&shell_name).ok_or_else(|| {
+        actix_web::error::ErrorBadRequest(format!("shell '{}' is not allowed", shell_name))
+    })?;
+    let profile = config.session_profile(user, shell);
+
+    let secs = request.timeout_secs.unwrap_or(config.command_timeout_secs);
+    if secs == 0 {
+        return Err(actix_web::error::ErrorBadRequest("timeoutSecs must be at least 1"));
+    }
+    let timeout = Duration::from_secs(secs.min(config.role(&profile.role).max_timeout_secs));
+    let max_bytes = request
+        .max_output_bytes
+        .map_or(config.output.max_bytes, |n| n.min(config.output.max_bytes));
+    let on_limit = config.output.on_limit;
+    let encoding = request.output_encoding;
+
+    let cwd = request
+        .cwd
+        .clone()
+        .or_else(|| profile.cwd.clone())
+        .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")));
+    if !cwd.is_dir() {
+        return Err(actix_web::error::ErrorBadRequest(format!("cwd '{}' is not a directory", cwd.display())));
+    }
+
+    let mut cmd = profile.shell.command(&request.command);
+    cmd.envs(&profile.env).envs(&request.env).current_dir(&cwd);
+    profile.limits.apply(&mut cmd);
+    exec::prepare(&mut cmd);
+    cmd.stdin(if request.stdin.is_some() { Stdio::piped() } else { Stdio::null() });
+
+    // Each command gets its own cgroup, so memory and pids limits apply and can be reported
+    let exec_id = Uuid::new_v4();
+    let cgroup = lobby
+        .send(ExecCgroup { exec_id, limits: profile.limits.clone() })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    if let Some(cgroup) = &cgroup {
+        cgroup.attach(&mut cmd);
+    }
+    let events_before = cgroup.as_ref().map(|c| c.events()).unwrap_or_default();
+
+    tracing::info!(user = profile.user.as_deref(), command = %request.command, "exec over REST");
+    let mut child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            std::thread::spawn(move || release_cgroup(cgroup));
+            let exit = ExitReport::spawn_failed();
+            METRICS.record_exit("exec", &exit);
+            return Ok(HttpResponse::Ok().json(serde_json::json!({
+                "command": request.command,
+                "currentDirectory": cwd,
+                "exitCode": -1,
+                "exit": exit.to_json(),
+                "notes": [format!("Failed to execute command: {}", e)]
+            })));
+        }
+    };
+
+    // The lobby tracks it like a session's command, so shutdown and the admin API reach it
+    let pid = child.id();
+    let started = ExecStarted {
+        exec_id,
+        pid,
+        user: profile.user.clone(),
+        command: request.command.clone(),
+    };
+    if !lobby.send(started).await.map_err(actix_web::error::ErrorInternalServerError)? {
+        exec::kill_pid_group(pid);
+        std::thread::spawn(move || {
+            let _ = child.wait();
+            exec::take_signal_sent(pid);
+            release_cgroup(cgroup);
+        });
+        return Err(actix_web::error::ErrorServiceUnavailable("server is shutting down"));
+    }
+    let lobby = lobby.get_ref().clone();
+
+    if let (Some(input), Some(mut pipe)) = (request.stdin, child.stdin.take()) {
+        // Written from its own thread so a command that doesn't read can't stall us
+        std::thread::spawn(move || {
+            let _ = pipe.write_all(input.as_bytes());
+        });
+    }
+
+    let accept = req.headers().get("accept").and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if !accept.contains("text/event-stream") {
+        // Reported from the blocking task so it happens even if the client hangs up
+        let captured = web::block(move || {
+            let captured = exec::capture(child, Some(timeout), max_bytes, on_limit, |_, _| {});
+            let events_after = release_cgroup(cgroup);
+            lobby.do_send(ExecFinished { exec_id });
+            (captured, events_after)
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+        let (captured, events_after) = captured;
+        let events = (events_before, events_after);
+        return Ok(HttpResponse::Ok().json(exec_result(exec_id, &request.command, &cwd, captured, encoding, timeout, events)));
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel::<Bytes>();
+    let (finished, finished_rx) = tokio::sync::oneshot::channel::<()>();
+    let watcher = tx.clone();
+    actix_web::rt::spawn(async move {
+        // A hang-up only shows when a write fails, so a command that prints
+        // nothing gets a comment frame every few seconds to find out
+        let mut keepalive = tokio::time::interval(SSE_KEEPALIVE);
+        tokio::pin!(finished_rx);
+        loop {
+            tokio::select! {
+                _ = watcher.closed() => {
+                    exec::kill_pid_group(pid);
+                    break;
+                }
+                _ = &mut finished_rx => break,
+                _ = keepalive.tick() => {
+                    let _ = watcher.send(Bytes::from_static(b": keepalive\n\n"));
+                }
+            }
+        }
+    });
+    std::thread::spawn(move || {
+        // Stream exactly what the buffered result would have held
+        let mut sent = 0u64;
+        let captured = exec::capture(child, Some(timeout), max_bytes, on_limit, |stream, data| {
+            let room = max_bytes.saturating_sub(sent) as usize;
+            if room == 0 {
+                return;
+            }
+            let data = &data[..data.len().min(room)];
+            sent += data.len() as u64;
+            let event = serde_json::json!({ "data": encode_output(data, encoding) });
+            if tx.send(sse_frame(stream.as_str(), &event)).is_err() {
+                // The client went away, so nobody will see the rest
+                exec::kill_pid_group(pid);
+            }
+        });
+        let _ = finished.send(());
+        let events = (events_before, release_cgroup(cgroup));
+        lobby.do_send(ExecFinished { exec_id });
+        let mut result = exec_result(exec_id, &request.command, &cwd, captured, encoding, timeout, events);
+        if let Some(result) = result.as_object_mut() {
+            result.remove("stdout");
+            result.remove("stderr");
+        }
+        let _ = tx.send(sse_frame("exit", &result));
+    });
+
+    let body = futures_util::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|frame| (Ok::<_, Error>(frame), rx))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(body))
+}
@@ -0,0 +1,76 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::VecDeque;
+use uuid::Uuid;
+
+/// Messages kept per room for late joiners
+const HISTORY_KEPT: usize = 200;
+/// Longest a message stays in a room's history
+const HISTORY_WINDOW_HOURS: i64 = 24;
+/// Longest message text accepted
+pub const MAX_TEXT_BYTES: usize = 4096;
+
+/// One chat line, with who sent it as they were when they sent it
+pub struct ChatMessage {
+    pub id: u64,
+    pub session: Uuid,
+    pub user: Option<String>,
+    pub display_name: String,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+impl ChatMessage {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "messageId": self.id,
+            "from": {
+                "sessionId": self.session,
+                "user": self.user,
+                "displayName": self.display_name
+            },
+            "text": self.text,
+            "timestamp": self.sent_at.to_rfc3339()
+        })
+    }
+}
+
+/// Check a message's text before it is sent anywhere
+pub fn validate_text(text: &str) -> Result<(), String> {
+    if text.trim().is_empty() {
+        return Err("text must not be empty".to_string());
+    }
+    if text.len() > MAX_TEXT_BYTES {
+        return Err(format!("text is longer than {} bytes", MAX_TEXT_BYTES));
+    }
+    Ok(())
+}
+
+/// A room's recent chat, bounded by count and age
+#[derive(Default)]
+pub struct ChatLog {
+    messages: VecDeque<ChatMessage>,
+}
+
+impl ChatLog {
+    pub fn push(&mut self, message: ChatMessage) -> &ChatMessage {
+        self.messages.push_back(message);
+        while self.messages.len() > HISTORY_KEPT {
+            self.messages.pop_front();
+        }
+        self.prune();
+        self.messages.back().expect("just pushed")
+    }
+
+    /// Messages still inside the window, oldest first
+    pub fn recent(&mut self) -> Vec<serde_json::Value> {
+        self.prune();
+        self.messages.iter().map(|m| m.to_json()).collect()
+    }
+
+    fn prune(&mut self) {
+        let cutoff = Utc::now() - Duration::hours(HISTORY_WINDOW_HOURS);
+        while self.messages.front().is_some_and(|m| m.sent_at < cutoff) {
+            self.messages.pop_front();
+        }
+    }
+}
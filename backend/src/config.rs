@@ -0,0 +1,281 @@
+use crate::exec::OutputLimits;
+use crate::jobs::JobLogConfig;
+use crate::limits::ResourceLimits;
+use crate::logging::LogConfig;
+use crate::room::RoomConfig;
+use crate::shell::Shell;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// Server-wide settings, read from the JSON file named by `HTTP_SSH_CONFIG`.
+/// Every field is optional; a missing file means "use the defaults".
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ServerConfig {
+    /// Shell used when neither the handshake nor the user profile picks one
+    pub default_shell: String,
+    /// Shells a session is allowed to request
+    pub allowed_shells: Vec<String>,
+    /// Extra or overriding shell definitions, keyed by name
+    pub shells: HashMap<String, ShellConfig>,
+    /// Known users; when non-empty, connecting requires a valid token
+    pub users: Vec<UserProfile>,
+    /// Default resource limits for every command
+    pub limits: ResourceLimits,
+    /// Parent cgroup (v2) for per-session cgroups, e.g. `/sys/fs/cgroup/http-ssh`
+    pub cgroup_root: Option<PathBuf>,
+    /// Cap on how much output one command may send back
+    pub output: OutputLimits,
+    /// Timeout for commands that don't ask for one
+    pub command_timeout_secs: u64,
+    /// What each role may do, keyed by role name
+    pub roles: HashMap<String, RolePolicy>,
+    /// Role given to sessions when no users are configured
+    pub anonymous_role: String,
+    /// Where background jobs spool their output
+    pub jobs: JobLogConfig,
+    /// Defaults for rooms created through the API
+    pub rooms: RoomConfig,
+    /// Key for signing invite links; a random one is used if unset
+    pub invite_secret: Option<String>,
+    /// File that keeps revoked invite ids across restarts
+    pub revoked_invites_file: Option<PathBuf>,
+    /// How long before an idle timeout or lifetime limit a session is warned
+    pub session_warning_secs: u64,
+    /// How long running commands get to finish on SIGTERM before they are signalled too
+    pub shutdown_grace_secs: u64,
+    /// Most sessions connected at once, across all rooms
+    pub max_sessions: usize,
+    /// Proxies whose `Forwarded`/`X-Forwarded-For` headers are believed
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Log format and levels
+    pub log: LogConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RolePolicy {
+    /// Longest `timeoutSecs` a command may ask for
+    pub max_timeout_secs: u64,
+    /// Whether commands may run detached, with no timeout
+    pub allow_detached: bool,
+    /// Whether the role may manage rooms it didn't create
+    pub admin: bool,
+    /// Disconnect sessions that send nothing for this long; 0 never does
+    pub idle_timeout_secs: u64,
+    /// Disconnect sessions this long after they connect, however busy; 0 never does
+    pub max_session_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ShellConfig {
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    /// Start from an empty environment instead of inheriting the server's
+    pub clear_env: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserProfile {
+    pub name: String,
+    pub token: String,
+    #[serde(default)]
+    pub shell: Option<String>,
+    /// Overrides for the server's default limits
+    #[serde(default)]
+    pub limits: ResourceLimits,
+    #[serde(default = "default_role")]
+    pub role: String,
+    /// Starting directory for the user's sessions and scheduled commands
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    /// Extra environment for everything the user runs
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+fn default_role() -> String {
+    "user".to_string()
+}
+
+/// Who a session belongs to and what it runs with, settled at connect time
+#[derive(Debug, Clone)]
+pub struct SessionProfile {
+    pub user: Option<String>,
+    pub role: String,
+    pub shell: Shell,
+    pub limits: ResourceLimits,
+    pub cwd: Option<PathBuf>,
+    pub env: HashMap<String, String>,
+    /// May watch and chat but not run commands or take control
+    pub read_only: bool,
+    /// Invite the session joined with, if any
+    pub invite: Option<Uuid>,
+    /// When the session is disconnected, e.g. its invite running out
+    pub expires_at: Option<DateTime<Utc>>,
+    /// Address of the peer that connected to the server
+    pub remote_addr: Option<String>,
+    /// Client address a trusted proxy forwarded, if the peer is one
+    pub forwarded_for: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let default_shell = if cfg!(target_os = "windows") { "cmd" } else { "bash" };
+        ServerConfig {
+            default_shell: default_shell.to_string(),
+            allowed_shells: vec![default_shell.to_string()],
+            shells: HashMap::new(),
+            users: Vec::new(),
+            limits: ResourceLimits::default(),
+            cgroup_root: None,
+            output: OutputLimits::default(),
+            command_timeout_secs: 15,
+            roles: HashMap::from([
+                ("user".to_string(), RolePolicy::default()),
+                (
+                    "admin".to_string(),
+                    RolePolicy {
+                        max_timeout_secs: 24 * 60 * 60,
+                        allow_detached: true,
+                        admin: true,
+                        ..RolePolicy::default()
+                    },
+                ),
+            ]),
+            anonymous_role: default_role(),
+            jobs: JobLogConfig::default(),
+            rooms: RoomConfig::default(),
+            invite_secret: None,
+            revoked_invites_file: None,
+            session_warning_secs: 60,
+            shutdown_grace_secs: 30,
+            max_sessions: 1000,
+            trusted_proxies: Vec::new(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for RolePolicy {
+    fn default() -> Self {
+        RolePolicy {
+            max_timeout_secs: 5 * 60,
+            allow_detached: false,
+            admin: false,
+            idle_timeout_secs: 30 * 60,
+            max_session_secs: 12 * 60 * 60,
+        }
+    }
+}
+
+impl Default for ShellConfig {
+    fn default() -> Self {
+        ShellConfig {
+            program: String::new(),
+            args: vec!["-c".to_string()],
+            env: HashMap::new(),
+            clear_env: false,
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn load() -> io::Result<ServerConfig> {
+        let config: ServerConfig = match std::env::var("HTTP_SSH_CONFIG") {
+            Ok(path) => {
+                let raw = std::fs::read_to_string(&path)?;
+                serde_json::from_str(&raw)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e)))?
+            }
+            Err(_) => ServerConfig::default(),
+        };
+
+        if config.shell(&config.default_shell).is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("default shell '{}' is not an allowed, known shell", config.default_shell),
+            ));
+        }
+
+        Ok(config)
+    }
+
+    /// Resolve a shell by name, if it is on the allowed list.
+    pub fn shell(&self, name: &str) -> Option<Shell> {
+        if !self.allowed_shells.iter().any(|s| s == name) {
+            return None;
+        }
+
+        match self.shells.get(name) {
+            Some(custom) => Some(Shell {
+                name: name.to_string(),
+                program: if custom.program.is_empty() {
+                    name.to_string()
+                } else {
+                    custom.program.clone()
+                },
+                args: custom.args.clone(),
+                env: custom.env.clone(),
+                clear_env: custom.clear_env,
+            }),
+            None => Shell::builtin(name),
+        }
+    }
+
+    /// Effective limits for a session, with the user's overrides applied
+    pub fn limits_for(&self, user: Option<&UserProfile>) -> ResourceLimits {
+        match user {
+            Some(user) => self.limits.merged(&user.limits),
+            None => self.limits.clone(),
+        }
+    }
+
+    pub fn session_profile(&self, user: Option<&UserProfile>, shell: Shell) -> SessionProfile {
+        SessionProfile {
+            user: user.map(|u| u.name.clone()),
+            role: user.map_or_else(|| self.anonymous_role.clone(), |u| u.role.clone()),
+            shell,
+            limits: self.limits_for(user),
+            cwd: user.and_then(|u| u.cwd.clone()),
+            env: user.map(|u| u.env.clone()).unwrap_or_default(),
+            read_only: false,
+            invite: None,
+            expires_at: None,
+            remote_addr: None,
+            forwarded_for: None,
+        }
+    }
+
+    pub fn find_user(&self, name: &str) -> Option<&UserProfile> {
+        self.users.iter().find(|u| u.name == name)
+    }
+
+    /// Policy for a role; roles missing from the config get the defaults
+    pub fn role(&self, name: &str) -> RolePolicy {
+        self.roles.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Look up the user owning `token`. Returns `Ok(None)` for anonymous
+    /// access, which is only allowed when no users are configured.
+    pub fn authenticate(&self, token: Option<&str>) -> Result<Option<&UserProfile>, &'static str> {
+        if self.users.is_empty() {
+            return Ok(None);
+        }
+
+        let token = token.ok_or("missing token")?;
+        self.users
+            .iter()
+            .find(|u| u.token == token)
+            .map(Some)
+            .ok_or("invalid token")
+    }
+}
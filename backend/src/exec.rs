@@ -0,0 +1,580 @@
+use crate::limits::CgroupEvents;
+use crate::message::OutputEncoding;
+use crate::metrics::METRICS;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How long to keep reading after the process exits, in case a background
+/// child it left behind is still holding the pipes open
+const EXIT_GRACE: Duration = Duration::from_millis(200);
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+const READ_CHUNK: usize = 8 * 1024;
+
+/// The last signal the server sent each process group, until its leader is reaped
+static SIGNALS_SENT: LazyLock<Mutex<HashMap<u32, i32>>> = LazyLock::new(Default::default);
+
+/// Server-side cap on how much output a single command may produce
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct OutputLimits {
+    /// Combined stdout + stderr bytes kept per command
+    pub max_bytes: u64,
+    pub on_limit: OnOutputLimit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OnOutputLimit {
+    /// Kill the command as soon as it goes over the cap
+    Kill,
+    /// Let it run to completion but drop everything past the cap
+    Discard,
+}
+
+impl Default for OutputLimits {
+    fn default() -> Self {
+        OutputLimits {
+            max_bytes: 4 * 1024 * 1024,
+            on_limit: OnOutputLimit::Kill,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Largest data payload in one binary output frame
+pub const FRAME_DATA_MAX: usize = 64 * 1024;
+const FRAME_KIND_OUTPUT: u8 = 1;
+const FRAME_HEADER_LEN: usize = 10;
+
+impl Stream {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+
+    fn code(self) -> u8 {
+        match self {
+            Stream::Stdout => 1,
+            Stream::Stderr => 2,
+        }
+    }
+}
+
+/// Binary WebSocket frame carrying raw output bytes. Layout (big-endian):
+/// `[kind = 1: u8][request id: u32][stream: u8, 1 = stdout, 2 = stderr][sequence: u32][data]`
+pub fn output_frame(request_id: u32, stream: Stream, sequence: u32, data: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + data.len());
+    frame.push(FRAME_KIND_OUTPUT);
+    frame.extend_from_slice(&request_id.to_be_bytes());
+    frame.push(stream.code());
+    frame.extend_from_slice(&sequence.to_be_bytes());
+    frame.extend_from_slice(data);
+    frame
+}
+
+enum ReaderEvent {
+    Chunk(Stream, Vec<u8>),
+    Eof,
+}
+
+/// What gets reported back to the client for one command
+#[derive(Debug, Default)]
+pub struct CommandResult {
+    pub request_id: u32,
+    pub command: String,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub exit_code: i32,
+    pub limit_exceeded: Option<&'static str>,
+    pub truncated: bool,
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    /// Server-side remarks (timeouts, limits), kept apart from the command's own output
+    pub notes: Vec<String>,
+    pub exit: ExitReport,
+    /// Who ran it, for rooms where everyone sees everyone's output
+    pub session: Uuid,
+    pub user: Option<String>,
+}
+
+impl CommandResult {
+    /// A failed result until told otherwise
+    pub fn new(request_id: u32, command: &str) -> CommandResult {
+        CommandResult {
+            request_id,
+            command: command.to_string(),
+            exit_code: -1,
+            ..Default::default()
+        }
+    }
+
+    pub fn add_note(&mut self, note: String) {
+        self.notes.push(note);
+    }
+}
+
+/// What the lobby needs to finish off a command once its worker is done
+pub struct PendingCommand {
+    pub result: CommandResult,
+    pub encoding: OutputEncoding,
+    pub timeout: Option<Duration>,
+    pub max_bytes: u64,
+    /// Where a tracked shell reports its working directory
+    pub state_file: Option<PathBuf>,
+    pub events_before: CgroupEvents,
+    /// Set when this is a background job rather than a foreground command
+    pub job_id: Option<u32>,
+    /// Whose shell state the command runs in: the session's, or its shared room's
+    pub shell_key: Uuid,
+    /// Server-assigned id of its `RunningCommand` entry
+    pub running_id: u32,
+}
+
+/// Everything we learned from running one command
+pub struct Captured {
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    /// Bytes the command actually wrote, including any we dropped
+    pub stdout_bytes: u64,
+    pub stderr_bytes: u64,
+    pub truncated: bool,
+    pub status: io::Result<ExitStatus>,
+    pub timed_out: bool,
+    /// The last signal the server sent the command's process group, if any
+    pub signal_sent: Option<i32>,
+    /// Wall-clock time from the start of capture until the process was reaped
+    pub duration: Duration,
+    pub usage: Option<ResourceUsage>,
+}
+
+/// CPU time and peak memory of a finished process and the children it waited for
+#[derive(Debug, Clone, Copy)]
+pub struct ResourceUsage {
+    pub user_cpu: Duration,
+    pub system_cpu: Duration,
+    pub max_rss_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ExitKind {
+    Exited,
+    Signaled,
+    TimedOut,
+    SpawnFailed,
+    /// Never got a status back, e.g. the wait itself failed
+    #[default]
+    Unknown,
+}
+
+impl ExitKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExitKind::Exited => "exited",
+            ExitKind::Signaled => "signaled",
+            ExitKind::TimedOut => "timedOut",
+            ExitKind::SpawnFailed => "spawnFailed",
+            ExitKind::Unknown => "unknown",
+        }
+    }
+}
+
+/// How a command ended, in more detail than an exit code
+#[derive(Debug, Clone, Default)]
+pub struct ExitReport {
+    pub kind: ExitKind,
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub core_dumped: bool,
+    pub duration: Duration,
+    pub usage: Option<ResourceUsage>,
+}
+
+impl ExitReport {
+    pub fn from_captured(captured: &Captured) -> ExitReport {
+        let mut report = ExitReport {
+            duration: captured.duration,
+            usage: captured.usage,
+            ..Default::default()
+        };
+
+        if let Ok(status) = &captured.status {
+            report.code = status.code();
+            #[cfg(unix)]
+            {
+                use std::os::unix::process::ExitStatusExt;
+                report.signal = status.signal();
+                report.core_dumped = status.core_dumped();
+            }
+            // A shell that outlives its child (our cwd tracking keeps one around)
+            // reports the child's death by signal N as exit code 128 + N. Only
+            // trust that when we did signal it; otherwise it is a genuine exit code.
+            if report.signal.is_none() && captured.signal_sent.is_some() {
+                report.signal = report.code.filter(|c| *c > 128).map(|c| c - 128).filter(|s| signal_name(*s).is_some());
+            }
+            report.kind = if report.signal.is_some() { ExitKind::Signaled } else { ExitKind::Exited };
+        }
+        // Timed out wins over the SIGKILL we sent to get there
+        if captured.timed_out {
+            report.kind = ExitKind::TimedOut;
+        }
+        report
+    }
+
+    /// A command the server answered itself, like an emulated `cd`
+    pub fn exited(code: i32) -> ExitReport {
+        ExitReport {
+            kind: ExitKind::Exited,
+            code: Some(code),
+            ..Default::default()
+        }
+    }
+
+    pub fn spawn_failed() -> ExitReport {
+        ExitReport {
+            kind: ExitKind::SpawnFailed,
+            ..Default::default()
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": self.kind.as_str(),
+            "code": self.code,
+            "signal": self.signal.and_then(signal_name),
+            "signalNumber": self.signal,
+            "coreDumped": self.core_dumped,
+            "durationMs": self.duration.as_millis() as u64,
+            "userCpuMs": self.usage.map(|u| u.user_cpu.as_millis() as u64),
+            "systemCpuMs": self.usage.map(|u| u.system_cpu.as_millis() as u64),
+            "maxRssBytes": self.usage.map(|u| u.max_rss_bytes)
+        })
+    }
+}
+
+/// Pipe the command's output and give it its own process group, so a
+/// timeout or output cap can take down everything it started.
+pub fn prepare(cmd: &mut Command) {
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        cmd.process_group(0);
+    }
+}
+
+/// Collect a spawned child's output under an optional `timeout` and an
+/// output cap, handing every chunk to `on_chunk` as it arrives.
+/// The child must have been set up with `prepare`.
+pub fn capture(
+    mut child: Child,
+    timeout: Option<Duration>,
+    max_bytes: u64,
+    on_limit: OnOutputLimit,
+    mut on_chunk: impl FnMut(Stream, &[u8]),
+) -> Captured {
+    let started = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let mut open_streams = 0;
+    if let Some(stdout) = child.stdout.take() {
+        spawn_reader(stdout, Stream::Stdout, tx.clone());
+        open_streams += 1;
+    }
+    if let Some(stderr) = child.stderr.take() {
+        spawn_reader(stderr, Stream::Stderr, tx.clone());
+        open_streams += 1;
+    }
+    drop(tx);
+
+    let mut captured = Captured {
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        stdout_bytes: 0,
+        stderr_bytes: 0,
+        truncated: false,
+        status: Err(io::Error::other("process was not waited on")),
+        timed_out: false,
+        signal_sent: None,
+        duration: Duration::ZERO,
+        usage: None,
+    };
+
+    let deadline = timeout.map(|t| Instant::now() + t);
+    let mut exited_at: Option<Instant> = None;
+    let mut killed = false;
+
+    while open_streams > 0 {
+        let now = Instant::now();
+        if deadline.is_some_and(|d| now >= d) {
+            captured.timed_out = true;
+            kill_group(&mut child);
+            break;
+        }
+        if exited_at.is_some_and(|at| now.duration_since(at) > EXIT_GRACE) {
+            break;
+        }
+
+        let wait = deadline.map_or(POLL_INTERVAL, |d| POLL_INTERVAL.min(d - now));
+        match rx.recv_timeout(wait) {
+            Ok(ReaderEvent::Chunk(stream, bytes)) => {
+                METRICS.output_bytes.with_label_values(&[stream.as_str()]).inc_by(bytes.len() as u64);
+                on_chunk(stream, &bytes);
+                let kept = captured.stdout.len() + captured.stderr.len();
+                let room = (max_bytes as usize).saturating_sub(kept);
+                let (buf, total) = match stream {
+                    Stream::Stdout => (&mut captured.stdout, &mut captured.stdout_bytes),
+                    Stream::Stderr => (&mut captured.stderr, &mut captured.stderr_bytes),
+                };
+                *total += bytes.len() as u64;
+                buf.extend_from_slice(&bytes[..bytes.len().min(room)]);
+
+                if bytes.len() > room {
+                    captured.truncated = true;
+                    if on_limit == OnOutputLimit::Kill && !killed {
+                        kill_group(&mut child);
+                        killed = true;
+                    }
+                }
+            }
+            Ok(ReaderEvent::Eof) => open_streams -= 1,
+            Err(RecvTimeoutError::Timeout) => {
+                if exited_at.is_none() && has_exited(&mut child) {
+                    exited_at = Some(Instant::now());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    if !captured.timed_out && !killed {
+        if let Some(deadline) = deadline {
+            while !has_exited(&mut child) {
+                if Instant::now() >= deadline {
+                    captured.timed_out = true;
+                    kill_group(&mut child);
+                    break;
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    (captured.status, captured.usage) = reap(&mut child);
+    captured.signal_sent = take_signal_sent(child.id());
+    captured.duration = started.elapsed();
+    captured
+}
+
+/// Whether the child has exited, without reaping it so `reap` can still collect its rusage
+#[cfg(unix)]
+fn has_exited(child: &mut Child) -> bool {
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    let result = unsafe { libc::waitid(libc::P_PID, child.id() as libc::id_t, &mut info, flags) };
+    // With WNOHANG, a child that is still running leaves si_pid at 0
+    result != 0 || unsafe { info.si_pid() } != 0
+}
+
+#[cfg(not(unix))]
+fn has_exited(child: &mut Child) -> bool {
+    !matches!(child.try_wait(), Ok(None))
+}
+
+/// Wait for the child and collect its resource usage
+#[cfg(unix)]
+fn reap(child: &mut Child) -> (io::Result<ExitStatus>, Option<ResourceUsage>) {
+    use std::os::unix::process::ExitStatusExt;
+
+    let mut status = 0;
+    let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+    loop {
+        let pid = unsafe { libc::wait4(child.id() as libc::pid_t, &mut status, 0, &mut rusage) };
+        if pid >= 0 {
+            break;
+        }
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::Interrupted {
+            return (Err(err), None);
+        }
+    }
+
+    let cpu = |t: libc::timeval| Duration::from_secs(t.tv_sec as u64) + Duration::from_micros(t.tv_usec as u64);
+    // Linux reports ru_maxrss in KiB, macOS in bytes
+    let rss_unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    let usage = ResourceUsage {
+        user_cpu: cpu(rusage.ru_utime),
+        system_cpu: cpu(rusage.ru_stime),
+        max_rss_bytes: rusage.ru_maxrss as u64 * rss_unit,
+    };
+    (Ok(ExitStatus::from_raw(status)), Some(usage))
+}
+
+#[cfg(not(unix))]
+fn reap(child: &mut Child) -> (io::Result<ExitStatus>, Option<ResourceUsage>) {
+    (child.wait(), None)
+}
+
+fn spawn_reader<R: Read + Send + 'static>(mut pipe: R, stream: Stream, tx: mpsc::Sender<ReaderEvent>) {
+    std::thread::spawn(move || {
+        let mut buf = vec![0u8; READ_CHUNK];
+        loop {
+            match pipe.read(&mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if tx.send(ReaderEvent::Chunk(stream, buf[..n].to_vec())).is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+        let _ = tx.send(ReaderEvent::Eof);
+    });
+}
+
+/// Kill the child and anything it started in its process group
+pub fn kill_group(child: &mut Child) {
+    kill_pid_group(child.id());
+    let _ = child.kill();
+}
+
+/// Kill a process group led by `pid`, for children owned by another thread
+pub fn kill_pid_group(pid: u32) {
+    #[cfg(unix)]
+    signal_pid_group(pid, libc::SIGKILL);
+    #[cfg(not(unix))]
+    let _ = pid;
+}
+
+#[cfg(unix)]
+pub fn signal_pid_group(pid: u32, signal: libc::c_int) {
+    SIGNALS_SENT.lock().unwrap_or_else(|e| e.into_inner()).insert(pid, signal);
+    unsafe {
+        libc::killpg(pid as libc::pid_t, signal);
+    }
+}
+
+/// Forget what was sent to a reaped process group, so a reused pid starts clean
+pub fn take_signal_sent(pid: u32) -> Option<i32> {
+    SIGNALS_SENT.lock().unwrap_or_else(|e| e.into_inner()).remove(&pid)
+}
+
+#[cfg(unix)]
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("SIGHUP", libc::SIGHUP),
+    ("SIGINT", libc::SIGINT),
+    ("SIGQUIT", libc::SIGQUIT),
+    ("SIGILL", libc::SIGILL),
+    ("SIGTRAP", libc::SIGTRAP),
+    ("SIGABRT", libc::SIGABRT),
+    ("SIGBUS", libc::SIGBUS),
+    ("SIGFPE", libc::SIGFPE),
+    ("SIGKILL", libc::SIGKILL),
+    ("SIGUSR1", libc::SIGUSR1),
+    ("SIGSEGV", libc::SIGSEGV),
+    ("SIGUSR2", libc::SIGUSR2),
+    ("SIGPIPE", libc::SIGPIPE),
+    ("SIGALRM", libc::SIGALRM),
+    ("SIGTERM", libc::SIGTERM),
+    ("SIGCHLD", libc::SIGCHLD),
+    ("SIGCONT", libc::SIGCONT),
+    ("SIGSTOP", libc::SIGSTOP),
+    ("SIGTSTP", libc::SIGTSTP),
+    ("SIGXCPU", libc::SIGXCPU),
+    ("SIGXFSZ", libc::SIGXFSZ),
+    ("SIGSYS", libc::SIGSYS),
+];
+
+/// Map a signal name like "TERM" or "SIGINT" to its number
+#[cfg(unix)]
+pub fn parse_signal(name: &str) -> Option<libc::c_int> {
+    let name = name.trim_start_matches("SIG");
+    SIGNALS.iter().find(|(n, _)| n[3..] == *name).map(|(_, signal)| *signal)
+}
+
+/// Name of a signal number, e.g. "SIGKILL"
+pub fn signal_name(signal: i32) -> Option<&'static str> {
+    #[cfg(unix)]
+    return SIGNALS.iter().find(|(_, s)| *s == signal).map(|(name, _)| *name);
+    #[cfg(not(unix))]
+    {
+        let _ = signal;
+        None
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    /// `raw` is a wait status: an exit code shifted left by 8, or a signal number
+    fn captured(raw: i32, signal_sent: Option<i32>, timed_out: bool) -> Captured {
+        Captured {
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            stdout_bytes: 0,
+            stderr_bytes: 0,
+            truncated: false,
+            status: Ok(ExitStatus::from_raw(raw)),
+            timed_out,
+            signal_sent,
+            duration: Duration::from_millis(5),
+            usage: None,
+        }
+    }
+
+    #[test]
+    fn plain_exit_codes() {
+        let report = ExitReport::from_captured(&captured(3 << 8, None, false));
+        assert_eq!((report.kind, report.code, report.signal), (ExitKind::Exited, Some(3), None));
+        assert_eq!(report.duration, Duration::from_millis(5));
+    }
+
+    #[test]
+    fn codes_over_128_are_exits_unless_the_server_signalled() {
+        let report = ExitReport::from_captured(&captured(130 << 8, None, false));
+        assert_eq!((report.kind, report.code, report.signal), (ExitKind::Exited, Some(130), None));
+
+        let report = ExitReport::from_captured(&captured(130 << 8, Some(libc::SIGINT), false));
+        assert_eq!((report.kind, report.code, report.signal), (ExitKind::Signaled, Some(130), Some(libc::SIGINT)));
+        assert_eq!(report.to_json()["signal"], "SIGINT");
+    }
+
+    #[test]
+    fn codes_that_name_no_signal_stay_exits() {
+        let report = ExitReport::from_captured(&captured(255 << 8, Some(libc::SIGTERM), false));
+        assert_eq!((report.kind, report.signal), (ExitKind::Exited, None));
+    }
+
+    #[test]
+    fn direct_signals_are_reported() {
+        let report = ExitReport::from_captured(&captured(libc::SIGKILL, None, false));
+        assert_eq!((report.kind, report.code, report.signal), (ExitKind::Signaled, None, Some(libc::SIGKILL)));
+    }
+
+    #[test]
+    fn timeouts_win_over_the_kill_that_ended_them() {
+        let report = ExitReport::from_captured(&captured(libc::SIGKILL, Some(libc::SIGKILL), true));
+        assert_eq!((report.kind, report.signal), (ExitKind::TimedOut, Some(libc::SIGKILL)));
+    }
+
+    #[test]
+    fn failed_waits_are_unknown() {
+        let mut failed = captured(0, None, false);
+        failed.status = Err(io::Error::other("wait failed"));
+        assert_eq!(ExitReport::from_captured(&failed).kind, ExitKind::Unknown);
+    }
+}
@@ -0,0 +1,484 @@
+use crate::config::{ServerConfig, SessionProfile};
+use crate::lobby::Lobby;
+use crate::message::{
+    ClientActorMessage, ClientSend, CloseSession, Connect, Disconnect, Hangup, Poll, PollResult, WsBinaryMessage,
+    WsMessage,
+};
+use crate::metrics::METRICS;
+use crate::startConn::{admit, ConnectParams};
+use actix::{
+    fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, Context, ContextFutureSpawner, Handler, Running, WrapFuture,
+};
+use actix_web::web::Bytes;
+use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use tokio::sync::watch;
+use tracing::Span;
+use uuid::Uuid;
+
+/// Events kept per session for clients that poll late or reconnect
+const EVENTS_KEPT: usize = 1000;
+/// A session nobody has polled or streamed for this long is closed, like a missed heartbeat
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(60);
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a closed session's last events stay readable
+const CLOSE_LINGER: Duration = Duration::from_secs(10);
+/// Longest a long-poll `GET` waits for something to happen
+const MAX_WAIT_SECS: u64 = 30;
+/// Sent on an idle SSE stream so proxies keep it open; also counts as a poll
+const SSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+struct Entry {
+    addr: Addr<HttpConn>,
+    /// Handed only to whoever opened the session; the session id is public
+    /// to everyone in the room, so it can't be the credential
+    secret: String,
+    user: Option<String>,
+}
+
+/// Open HTTP sessions, so later requests can find their actor
+#[derive(Default)]
+pub struct HttpSessions(Mutex<HashMap<Uuid, Entry>>);
+
+impl HttpSessions {
+    /// Register a session and return its secret
+    fn insert(&self, id: Uuid, addr: Addr<HttpConn>, user: Option<String>) -> String {
+        let secret = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        self.0.lock().unwrap().insert(id, Entry { addr, secret: secret.clone(), user });
+        secret
+    }
+
+    fn remove(&self, id: &Uuid) {
+        self.0.lock().unwrap().remove(id);
+    }
+
+    /// A session's actor, if the caller may use it: every request needs the
+    /// session's secret, and a user's session also needs that user's token
+    fn find(&self, id: &Uuid, params: &SessionParams, config: &ServerConfig) -> Result<Addr<HttpConn>, Error> {
+        let sessions = self.0.lock().unwrap();
+        let entry = sessions.get(id).ok_or_else(|| actix_web::error::ErrorNotFound("no such session"))?;
+        let secret_ok: bool = params.secret.as_deref().is_some_and(|s| s.as_bytes().ct_eq(entry.secret.as_bytes()).into());
+        if !secret_ok {
+            METRICS.auth_failed("api");
+            return Err(actix_web::error::ErrorForbidden("wrong or missing session secret"));
+        }
+        if let Some(owner) = &entry.user {
+            let token = params.token.as_deref();
+            let user = config.authenticate(token).map_err(|e| {
+                METRICS.auth_failed("api");
+                actix_web::error::ErrorUnauthorized(e)
+            })?;
+            if user.map(|u| &u.name) != Some(owner) {
+                return Err(actix_web::error::ErrorForbidden("session belongs to another user"));
+            }
+        }
+        Ok(entry.addr.clone())
+    }
+}
+
+/// The last `EVENTS_KEPT` events sent to one session, as `(id, JSON text)`;
+/// ids count up from 1
+#[derive(Default)]
+struct EventBuffer {
+    events: VecDeque<(u64, String)>,
+    last: u64,
+}
+
+impl EventBuffer {
+    fn push(&mut self, event: String) -> u64 {
+        self.last += 1;
+        self.events.push_back((self.last, event));
+        while self.events.len() > EVENTS_KEPT {
+            self.events.pop_front();
+        }
+        self.last
+    }
+
+    /// Events after `after`, and whether some between it and them were already dropped
+    fn since(&self, after: u64) -> (Vec<(u64, String)>, bool) {
+        let oldest = self.events.front().map_or(self.last + 1, |(id, _)| *id);
+        let events = self.events.iter().filter(|(id, _)| *id > after).cloned().collect();
+        (events, after + 1 < oldest)
+    }
+}
+
+/// A session whose client talks plain HTTP. To the lobby it looks just like a
+/// `WsConn`; what the lobby sends is buffered until the client polls or streams it.
+pub struct HttpConn {
+    id: Uuid,
+    room: Uuid,
+    lobby_addr: Addr<Lobby>,
+    profile: SessionProfile,
+    shared: bool,
+    events: EventBuffer,
+    changes: watch::Sender<u64>,
+    last_seen: Instant,
+    closed: bool,
+    registry: web::Data<HttpSessions>,
+    span: Span,
+}
+
+impl HttpConn {
+    fn new(
+        room: Uuid,
+        lobby: Addr<Lobby>,
+        profile: SessionProfile,
+        shared: bool,
+        registry: web::Data<HttpSessions>,
+    ) -> HttpConn {
+        let id = Uuid::new_v4();
+        HttpConn {
+            span: tracing::info_span!("session", session_id = %id, user = profile.user.as_deref(), room = %room, transport = "http"),
+            id,
+            room,
+            lobby_addr: lobby,
+            profile,
+            shared,
+            events: EventBuffer::default(),
+            changes: watch::channel(0).0,
+            last_seen: Instant::now(),
+            closed: false,
+            registry,
+        }
+    }
+
+    fn push(&mut self, event: String) {
+        let id = self.events.push(event);
+        self.changes.send_replace(id);
+    }
+
+    /// End the session the way a WebSocket close frame would, keeping the last
+    /// events around for a little while so the client can still read them
+    fn close(&mut self, ctx: &mut Context<Self>, code: u16, reason: &str) {
+        if self.closed {
+            return;
+        }
+        let event = serde_json::json!({ "type": "closed", "payload": { "code": code, "reason": reason } });
+        self.push(event.to_string());
+        self.closed = true;
+        self.lobby_addr.do_send(Disconnect { self_id: self.id, lobby_id: self.room });
+        ctx.run_later(CLOSE_LINGER, |_, ctx| ctx.stop());
+    }
+}
+
+impl Actor for HttpConn {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        tracing::debug!("HTTP session started");
+
+        ctx.run_interval(SWEEP_INTERVAL, |act, ctx| {
+            if !act.closed && act.last_seen.elapsed() > CLIENT_TIMEOUT {
+                let _span = act.span.clone().entered();
+                tracing::info!("closing HTTP session nobody is polling");
+                METRICS.heartbeat_timeouts.inc();
+                act.close(ctx, 1001, "client stopped polling");
+            }
+        });
+
+        let addr = ctx.address();
+        self.lobby_addr
+            .send(Connect {
+                addr: addr.clone().recipient(),
+                binary_addr: addr.clone().recipient(),
+                close_addr: addr.recipient(),
+                lobby_id: self.room,
+                self_id: self.id,
+                profile: self.profile.clone(),
+                shared: self.shared,
+            })
+            .into_actor(self)
+            .then(|res, _, ctx| {
+                if res.is_err() {
+                    ctx.stop();
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        if !self.closed {
+            self.lobby_addr.do_send(Disconnect { self_id: self.id, lobby_id: self.room });
+        }
+        self.registry.remove(&self.id);
+        Running::Stop
+    }
+}
+
+impl Handler<WsMessage> for HttpConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsMessage, _: &mut Context<Self>) {
+        self.push(msg.message);
+    }
+}
+
+impl Handler<WsBinaryMessage> for HttpConn {
+    type Result = ();
+
+    /// Binary frames can't ride in SSE, so they arrive base64-encoded
+    fn handle(&mut self, msg: WsBinaryMessage, _: &mut Context<Self>) {
+        let event = serde_json::json!({ "type": "binary_frame", "payload": { "data": BASE64.encode(&msg.data) } });
+        self.push(event.to_string());
+    }
+}
+
+impl Handler<CloseSession> for HttpConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Context<Self>) {
+        self.close(ctx, msg.code.into(), &msg.reason);
+    }
+}
+
+impl Handler<Poll> for HttpConn {
+    type Result = actix::MessageResult<Poll>;
+
+    fn handle(&mut self, msg: Poll, _: &mut Context<Self>) -> Self::Result {
+        self.last_seen = Instant::now();
+        let (events, missed) = self.events.since(msg.after);
+        actix::MessageResult(PollResult {
+            events,
+            missed,
+            closed: self.closed,
+            changes: self.changes.subscribe(),
+        })
+    }
+}
+
+impl Handler<ClientSend> for HttpConn {
+    type Result = bool;
+
+    fn handle(&mut self, msg: ClientSend, _: &mut Context<Self>) -> bool {
+        if self.closed {
+            return false;
+        }
+        self.last_seen = Instant::now();
+        // Keepalives are answered here, as WsConn does, so they never count as activity
+        let kind = serde_json::from_str::<serde_json::Value>(&msg.text)
+            .ok()
+            .and_then(|parsed| parsed.get("type").and_then(|t| t.as_str()).map(str::to_owned));
+        match kind.as_deref() {
+            Some("pong") => {}
+            Some("ping") => {
+                let pong_message = serde_json::json!({
+                    "type": "pong",
+                    "timestamp": chrono::Utc::now().timestamp(),
+                    "server_id": "http-ssh-server"
+                });
+                self.push(pong_message.to_string());
+            }
+            _ => self.lobby_addr.do_send(ClientActorMessage { id: self.id, msg: msg.text, room_id: self.room }),
+        }
+        true
+    }
+}
+
+impl Handler<Hangup> for HttpConn {
+    type Result = ();
+
+    fn handle(&mut self, _: Hangup, ctx: &mut Context<Self>) {
+        self.close(ctx, 1000, "closed by client");
+    }
+}
+
+/// Join a room without a WebSocket; takes the same query parameters as `/ws/{room}`
+#[post("/api/rooms/{room}/sessions")]
+pub async fn open_session(
+    req: HttpRequest,
+    path: web::Path<String>,
+    params: web::Query<ConnectParams>,
+    config: web::Data<ServerConfig>,
+    sessions: web::Data<HttpSessions>,
+) -> Result<HttpResponse, Error> {
+    let admitted = admit(&req, path.into_inner(), &params, &config, "api").await?;
+    let user = admitted.profile.user.clone();
+    let conn = HttpConn::new(admitted.room, admitted.lobby, admitted.profile, params.shared, sessions.clone());
+    let id = conn.id;
+    let secret = sessions.insert(id, conn.start(), user);
+
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "sessionId": id,
+        "secret": secret,
+        "roomId": admitted.room,
+        "commands": format!("/api/sessions/{}/commands", id),
+        "events": format!("/api/sessions/{}/events", id)
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct SessionParams {
+    secret: Option<String>,
+    token: Option<String>,
+}
+
+/// Send one message, exactly as it would go over the WebSocket
+#[post("/api/sessions/{id}/commands")]
+pub async fn send_message(
+    path: web::Path<Uuid>,
+    params: web::Query<SessionParams>,
+    body: web::Json<serde_json::Value>,
+    config: web::Data<ServerConfig>,
+    sessions: web::Data<HttpSessions>,
+) -> Result<HttpResponse, Error> {
+    let addr = sessions.find(&path, &params, &config)?;
+    let accepted = addr
+        .send(ClientSend { text: body.into_inner().to_string() })
+        .await
+        .map_err(|_| actix_web::error::ErrorGone("session has closed"))?;
+    if !accepted {
+        return Err(actix_web::error::ErrorGone("session has closed"));
+    }
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventParams {
+    #[serde(flatten)]
+    session: SessionParams,
+    /// Last event id already seen
+    #[serde(default)]
+    after: u64,
+    /// How long a long poll may wait for an event
+    wait_secs: Option<u64>,
+}
+
+/// The session's events: an SSE stream when asked for `text/event-stream`,
+/// otherwise a long poll that returns as soon as there is anything after `after`
+#[get("/api/sessions/{id}/events")]
+pub async fn session_events(
+    req: HttpRequest,
+    path: web::Path<Uuid>,
+    params: web::Query<EventParams>,
+    config: web::Data<ServerConfig>,
+    sessions: web::Data<HttpSessions>,
+) -> Result<HttpResponse, Error> {
+    let addr = sessions.find(&path, &params.session, &config)?;
+    let gone = |_| actix_web::error::ErrorGone("session has closed");
+
+    let accept = req.headers().get("accept").and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if accept.contains("text/event-stream") {
+        // A reconnecting `EventSource` says where it left off
+        let after = req
+            .headers()
+            .get("last-event-id")
+            .and_then(|v| v.to_str().ok()?.parse().ok())
+            .unwrap_or(params.after);
+        return Ok(HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header(("Cache-Control", "no-cache"))
+            .streaming(event_stream(addr, after)));
+    }
+
+    let wait = Duration::from_secs(params.wait_secs.unwrap_or(25).min(MAX_WAIT_SECS));
+    let mut poll = addr.send(Poll { after: params.after }).await.map_err(gone)?;
+    if poll.events.is_empty() && !poll.closed && !wait.is_zero() {
+        let _ = tokio::time::timeout(wait, poll.changes.changed()).await;
+        poll = addr.send(Poll { after: params.after }).await.map_err(gone)?;
+    }
+
+    let next = poll.events.last().map_or(params.after, |(id, _)| *id);
+    let events: Vec<serde_json::Value> = poll
+        .events
+        .iter()
+        .map(|(id, text)| {
+            let event: serde_json::Value = serde_json::from_str(text).unwrap_or_default();
+            serde_json::json!({ "id": id, "event": event })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "events": events,
+        "next": next,
+        "missed": poll.missed,
+        "closed": poll.closed
+    })))
+}
+
+/// SSE frames for everything after `after`, ending once the session has closed
+fn event_stream(addr: Addr<HttpConn>, after: u64) -> impl futures_util::Stream<Item = Result<Bytes, Error>> {
+    futures_util::stream::unfold(Some((addr, after)), |state| async move {
+        let (addr, after) = state?;
+        let mut poll = addr.send(Poll { after }).await.ok()?;
+        if poll.events.is_empty() && !poll.closed {
+            if tokio::time::timeout(SSE_KEEPALIVE, poll.changes.changed()).await.is_err() {
+                return Some((Ok(Bytes::from_static(b": keepalive\n\n")), Some((addr, after))));
+            }
+            poll = addr.send(Poll { after }).await.ok()?;
+        }
+
+        let mut frames = String::new();
+        if poll.missed {
+            frames.push_str("event: missed\ndata: {}\n\n");
+        }
+        for (id, text) in &poll.events {
+            frames.push_str(&format!("id: {}\ndata: {}\n\n", id, text));
+        }
+        let next = poll.events.last().map_or(after, |(id, _)| *id);
+        let state = (!poll.closed).then_some((addr, next));
+        Some((Ok(Bytes::from(frames)), state))
+    })
+}
+
+/// Leave the room and end the session
+#[delete("/api/sessions/{id}")]
+pub async fn close_session(
+    path: web::Path<Uuid>,
+    params: web::Query<SessionParams>,
+    config: web::Data<ServerConfig>,
+    sessions: web::Data<HttpSessions>,
+) -> Result<HttpResponse, Error> {
+    let addr = sessions.find(&path, &params, &config)?;
+    addr.do_send(Hangup);
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(events: usize) -> EventBuffer {
+        let mut buffer = EventBuffer::default();
+        for n in 0..events {
+            buffer.push(n.to_string());
+        }
+        buffer
+    }
+
+    #[test]
+    fn nothing_is_missed_while_every_event_is_kept() {
+        let buffer = buffer(3);
+        let (events, missed) = buffer.since(0);
+        assert_eq!(events.iter().map(|(id, _)| *id).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(!missed);
+
+        let (events, missed) = buffer.since(3);
+        assert!(events.is_empty() && !missed);
+    }
+
+    #[test]
+    fn an_empty_buffer_misses_nothing() {
+        let (events, missed) = EventBuffer::default().since(0);
+        assert!(events.is_empty() && !missed);
+    }
+
+    #[test]
+    fn dropped_events_are_reported_as_missed() {
+        let buffer = buffer(EVENTS_KEPT + 5);
+        let (events, missed) = buffer.since(0);
+        assert_eq!(events.len(), EVENTS_KEPT);
+        assert_eq!(events[0].0, 6);
+        assert!(missed);
+
+        // Resuming right before the oldest kept event loses nothing
+        assert!(!buffer.since(5).1);
+        assert!(buffer.since(4).1);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::exec::ExitReport;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Most shells one fan-out may run in
+pub const MAX_TARGETS: usize = 256;
+
+/// Where one copy of a fan-out command runs
+pub struct FanoutTarget {
+    /// Session whose shell, limits and cgroup the command runs with
+    pub session: Uuid,
+    pub room: Uuid,
+    pub user: Option<String>,
+    pub shared: bool,
+    pub pid: Option<u32>,
+    result: Option<TargetResult>,
+}
+
+impl FanoutTarget {
+    pub fn new(session: Uuid, room: Uuid, user: Option<String>, shared: bool) -> FanoutTarget {
+        FanoutTarget { session, room, user, shared, pid: None, result: None }
+    }
+}
+
+/// How one copy of the command ended
+pub struct TargetResult {
+    pub exit: ExitReport,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub truncated: bool,
+    pub notes: Vec<String>,
+}
+
+/// One command sent to many shells, waiting on all of them
+pub struct Fanout {
+    pub id: u32,
+    pub request_id: u32,
+    pub initiator: Uuid,
+    pub command: String,
+    pub started_at: DateTime<Utc>,
+    pub targets: Vec<FanoutTarget>,
+}
+
+impl Fanout {
+    pub fn is_done(&self) -> bool {
+        self.targets.iter().all(|t| t.result.is_some())
+    }
+
+    pub fn target_json(&self, target: &FanoutTarget) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "sessionId": target.session,
+            "roomId": target.room,
+            "user": target.user,
+            "shared": target.shared,
+            "done": target.result.is_some()
+        });
+        if let Some(result) = &target.result {
+            json["exitCode"] = serde_json::json!(result.exit.code);
+            json["exit"] = result.exit.to_json();
+            json["stdout"] = serde_json::json!(String::from_utf8_lossy(&result.stdout));
+            json["stderr"] = serde_json::json!(String::from_utf8_lossy(&result.stderr));
+            json["truncated"] = serde_json::json!(result.truncated);
+            json["notes"] = serde_json::json!(result.notes);
+        }
+        json
+    }
+
+    /// Every target's result, plus the targets grouped by exit code and stdout.
+    /// The largest group comes first; anything outside it is an odd one out.
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut groups: Vec<(Option<i32>, &[u8], Vec<Uuid>)> = Vec::new();
+        for target in &self.targets {
+            let Some(result) = &target.result else { continue };
+            let key = (result.exit.code, result.stdout.as_slice());
+            match groups.iter_mut().find(|(code, stdout, _)| (*code, *stdout) == key) {
+                Some((_, _, sessions)) => sessions.push(target.session),
+                None => groups.push((key.0, key.1, vec![target.session])),
+            }
+        }
+        groups.sort_by_key(|(_, _, sessions)| std::cmp::Reverse(sessions.len()));
+
+        let exit_codes = self.targets.iter().filter_map(|t| t.result.as_ref()).fold(
+            HashMap::<String, usize>::new(),
+            |mut counts, r| {
+                let code = r.exit.code.map_or_else(|| r.exit.kind.as_str().to_string(), |c| c.to_string());
+                *counts.entry(code).or_default() += 1;
+                counts
+            },
+        );
+
+        serde_json::json!({
+            "fanoutId": self.id,
+            "requestId": self.request_id,
+            "command": self.command,
+            "startedAt": self.started_at.to_rfc3339(),
+            "durationMs": (Utc::now() - self.started_at).num_milliseconds(),
+            "targets": self.targets.iter().map(|t| self.target_json(t)).collect::<Vec<_>>(),
+            "exitCodes": exit_codes,
+            "groups": groups.iter().map(|(code, _, sessions)| serde_json::json!({
+                "exitCode": code,
+                "count": sessions.len(),
+                "sessions": sessions
+            })).collect::<Vec<_>>(),
+            "uniform": groups.len() <= 1
+        })
+    }
+}
+
+/// Fan-outs with copies still running, by id
+#[derive(Default)]
+pub struct FanoutRegistry {
+    next_id: u32,
+    running: HashMap<u32, Fanout>,
+}
+
+impl FanoutRegistry {
+    pub fn start(&mut self, request_id: u32, initiator: Uuid, command: &str, targets: Vec<FanoutTarget>) -> u32 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.running.insert(id, Fanout {
+            id,
+            request_id,
+            initiator,
+            command: command.to_string(),
+            started_at: Utc::now(),
+            targets,
+        });
+        id
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Fanout> {
+        self.running.get_mut(&id)
+    }
+
+    /// Store one target's result; the index is its place in `targets`
+    pub fn record(&mut self, id: u32, index: usize, result: TargetResult) -> Option<&Fanout> {
+        let fanout = self.running.get_mut(&id)?;
+        fanout.targets.get_mut(index)?.result = Some(result);
+        Some(fanout)
+    }
+
+    /// Forget a fan-out once every target has reported
+    pub fn take_done(&mut self, id: u32) -> Option<Fanout> {
+        if self.running.get(&id)?.is_done() {
+            self.running.remove(&id)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(code: i32, stdout: &str) -> TargetResult {
+        TargetResult {
+            exit: ExitReport::exited(code),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: Vec::new(),
+            truncated: false,
+            notes: Vec::new(),
+        }
+    }
+
+    fn start(registry: &mut FanoutRegistry, targets: usize) -> (u32, Vec<Uuid>) {
+        let sessions: Vec<Uuid> = (0..targets).map(|_| Uuid::new_v4()).collect();
+        let targets = sessions.iter().map(|s| FanoutTarget::new(*s, Uuid::new_v4(), None, false)).collect();
+        (registry.start(1, Uuid::new_v4(), "uptime", targets), sessions)
+    }
+
+    #[test]
+    fn groups_by_exit_code_and_stdout_largest_first() {
+        let mut registry = FanoutRegistry::default();
+        let (id, sessions) = start(&mut registry, 4);
+        registry.record(id, 0, result(1, "down\n"));
+        registry.record(id, 1, result(0, "up\n"));
+        registry.record(id, 2, result(0, "up\n"));
+        registry.record(id, 3, result(0, "up, slowly\n"));
+
+        let json = registry.take_done(id).unwrap().to_json();
+        let groups = json["groups"].as_array().unwrap();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0]["count"], 2);
+        assert_eq!(groups[0]["exitCode"], 0);
+        assert_eq!(groups[0]["sessions"], serde_json::json!([sessions[1], sessions[2]]));
+        assert_eq!(json["exitCodes"], serde_json::json!({ "0": 3, "1": 1 }));
+        assert_eq!(json["uniform"], false);
+    }
+
+    #[test]
+    fn matching_targets_are_uniform() {
+        let mut registry = FanoutRegistry::default();
+        let (id, _) = start(&mut registry, 3);
+        for index in 0..3 {
+            registry.record(id, index, result(0, "same\n"));
+        }
+        let json = registry.take_done(id).unwrap().to_json();
+        assert_eq!(json["groups"].as_array().unwrap().len(), 1);
+        assert_eq!(json["uniform"], true);
+    }
+
+    #[test]
+    fn stays_running_until_every_target_reports() {
+        let mut registry = FanoutRegistry::default();
+        let (id, _) = start(&mut registry, 2);
+        registry.record(id, 0, result(0, ""));
+        assert!(registry.take_done(id).is_none());
+        assert!(registry.record(id, 5, result(0, "")).is_none());
+
+        registry.record(id, 1, result(0, ""));
+        assert!(registry.take_done(id).is_some());
+        assert!(registry.get_mut(id).is_none());
+    }
+}
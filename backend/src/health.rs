@@ -0,0 +1,129 @@
+use crate::config::ServerConfig;
+use crate::lobby::Lobby;
+use crate::message::HealthProbe;
+use crate::shutdown::Draining;
+use actix::Addr;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+
+/// How long the lobby may take to answer before it counts as stuck
+const LOBBY_DEADLINE: Duration = Duration::from_secs(1);
+/// How long a shell gets to run an empty script before it is killed
+const SHELL_DEADLINE: Duration = Duration::from_secs(2);
+
+/// When this process started, for uptime
+pub struct Started {
+    at: DateTime<Utc>,
+    instant: Instant,
+}
+
+impl Started {
+    pub fn now() -> Started {
+        Started { at: Utc::now(), instant: Instant::now() }
+    }
+}
+
+/// Version, uptime and build details shared by both probes
+fn about(started: &Started) -> serde_json::Value {
+    serde_json::json!({
+        "service": "http-ssh-server",
+        "version": env!("CARGO_PKG_VERSION"),
+        "startedAt": started.at.to_rfc3339(),
+        "uptimeSecs": started.instant.elapsed().as_secs(),
+        "build": {
+            "package": env!("CARGO_PKG_NAME"),
+            "profile": if cfg!(debug_assertions) { "debug" } else { "release" },
+            // Set by the release build, e.g. `HTTP_SSH_GIT_COMMIT=$(git rev-parse HEAD) cargo build`
+            "commit": option_env!("HTTP_SSH_GIT_COMMIT")
+        }
+    })
+}
+
+/// One readiness check's outcome
+fn check(name: &str, result: Result<String, String>) -> serde_json::Value {
+    let ok = result.is_ok();
+    let detail = result.unwrap_or_else(|e| e);
+    serde_json::json!({ "name": name, "ok": ok, "detail": detail })
+}
+
+/// Check that a shell's binary can be started. Shells don't share a no-op, so it
+/// gets an empty script, and how that exits says nothing about readiness.
+async fn try_shell(config: &ServerConfig, name: &str) -> Result<String, String> {
+    let shell = config.shell(name).ok_or("not a known shell")?;
+    let mut cmd = tokio::process::Command::from(shell.command(""));
+    cmd.stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).kill_on_drop(true);
+    let mut child = cmd.spawn().map_err(|e| format!("{}: {}", shell.program, e))?;
+    // Reap it if it is quick; if not, dropping it kills it
+    let _ = tokio::time::timeout(SHELL_DEADLINE, child.wait()).await;
+    Ok(shell.program)
+}
+
+/// Create and remove a file, off the async runtime
+async fn try_write(dir: std::path::PathBuf) -> Result<String, String> {
+    web::block(move || {
+        let probe = dir.join(format!(".health-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&probe, b"ok").and_then(|_| std::fs::remove_file(&probe))?;
+        Ok::<_, std::io::Error>(dir.display().to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// The process is up and serving HTTP; nothing else is checked. Also served
+/// on the older `/health`, which the frontend polls.
+pub async fn live(started: web::Data<Started>) -> HttpResponse {
+    let mut body = about(&started);
+    body["status"] = serde_json::json!("alive");
+    HttpResponse::Ok().json(body)
+}
+
+/// Whether new sessions would be served: 200 if every check passes, else 503
+pub async fn ready(
+    started: web::Data<Started>,
+    config: web::Data<ServerConfig>,
+    lobby: web::Data<Addr<Lobby>>,
+    draining: web::Data<Draining>,
+) -> HttpResponse {
+    let mut checks = Vec::new();
+
+    checks.push(check(
+        "shutdown",
+        if draining.is_draining() { Err("server is shutting down".to_string()) } else { Ok("running".to_string()) },
+    ));
+
+    let probe = lobby.send(HealthProbe).timeout(LOBBY_DEADLINE).await;
+    let sessions = probe.as_ref().ok().map(|p| p.sessions);
+    checks.push(check(
+        "lobby",
+        probe
+            .as_ref()
+            .map(|p| format!("{} sessions in {} rooms", p.sessions, p.rooms))
+            .map_err(|e| e.to_string()),
+    ));
+    checks.push(check(
+        "capacity",
+        match sessions {
+            Some(n) if n >= config.max_sessions => Err(format!("{} of {} sessions", n, config.max_sessions)),
+            Some(n) => Ok(format!("{} of {} sessions", n, config.max_sessions)),
+            None => Err("lobby did not answer".to_string()),
+        },
+    ));
+
+    for name in &config.allowed_shells {
+        checks.push(check(&format!("shell:{}", name), try_shell(&config, name).await));
+    }
+    checks.push(check("jobLogDir", try_write(config.jobs.log_dir.clone()).await));
+
+    let ready = checks.iter().all(|c| c["ok"] == true);
+    let mut body = about(&started);
+    body["status"] = serde_json::json!(if ready { "ready" } else { "unavailable" });
+    body["checks"] = serde_json::json!(checks);
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
@@ -0,0 +1,273 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64URL, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+/// How long an invite lasts when the request doesn't say
+pub const DEFAULT_INVITE_SECS: u64 = 30 * 60;
+pub const MAX_INVITE_SECS: u64 = 7 * 24 * 60 * 60;
+/// Expired and revoked invites kept for `GET /api/invites`
+const INVITES_KEPT: usize = 200;
+
+/// What an invite lets its holder do in the room
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum InviteRole {
+    /// Watch the terminal and chat, but never type
+    #[default]
+    Viewer,
+    Member,
+}
+
+/// What a signed invite token says
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InviteClaims {
+    pub id: Uuid,
+    pub room: Uuid,
+    pub role: InviteRole,
+    /// Unix seconds
+    pub expires_at: i64,
+}
+
+impl InviteClaims {
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp(self.expires_at, 0)
+    }
+}
+
+/// Signs invite tokens as `<base64url claims>.<base64url HMAC-SHA256>`
+pub struct InviteSigner {
+    key: Vec<u8>,
+}
+
+impl InviteSigner {
+    /// Without a configured secret, tokens only stay valid until the server restarts
+    pub fn new(secret: Option<&str>) -> InviteSigner {
+        let key = match secret {
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => [Uuid::new_v4().into_bytes(), Uuid::new_v4().into_bytes()].concat(),
+        };
+        InviteSigner { key }
+    }
+
+    fn mac(&self) -> Hmac<Sha256> {
+        Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length")
+    }
+
+    pub fn sign(&self, claims: &InviteClaims) -> String {
+        let body = BASE64URL.encode(serde_json::to_vec(claims).expect("claims serialize"));
+        let mut mac = self.mac();
+        mac.update(body.as_bytes());
+        format!("{}.{}", body, BASE64URL.encode(mac.finalize().into_bytes()))
+    }
+
+    /// Check a token's signature and expiry and hand back its claims
+    pub fn verify(&self, token: &str) -> Result<InviteClaims, String> {
+        let (body, signature) = token.split_once('.').ok_or("malformed invite")?;
+        let signature = BASE64URL.decode(signature).map_err(|_| "malformed invite")?;
+        let mut mac = self.mac();
+        mac.update(body.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| "invalid invite signature")?;
+
+        let claims: InviteClaims = BASE64URL
+            .decode(body)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .ok_or("malformed invite")?;
+        if claims.expires_at <= Utc::now().timestamp() {
+            return Err("invite has expired".to_string());
+        }
+        Ok(claims)
+    }
+}
+
+/// Looks like a signed token rather than a room's plain invite code
+pub fn is_signed(token: &str) -> bool {
+    token.contains('.')
+}
+
+/// An invite as the server remembers it; the token itself is never kept
+pub struct IssuedInvite {
+    pub claims: InviteClaims,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl IssuedInvite {
+    pub fn to_json(&self, revoked: bool) -> serde_json::Value {
+        serde_json::json!({
+            "inviteId": self.claims.id,
+            "roomId": self.claims.room,
+            "role": self.claims.role,
+            "expiresAt": self.claims.expires_at().map(|t| t.to_rfc3339()),
+            "createdBy": self.created_by,
+            "createdAt": self.created_at.to_rfc3339(),
+            "revoked": revoked
+        })
+    }
+}
+
+/// Invites minted so far, and the ones taken back
+#[derive(Default)]
+pub struct InviteRegistry {
+    /// Oldest first
+    issued: Vec<IssuedInvite>,
+    /// Revoked ids, each with the unix time after which no token carrying it can be valid
+    revoked: HashMap<Uuid, i64>,
+    /// Where `revoked` is kept across restarts
+    file: Option<PathBuf>,
+}
+
+impl InviteRegistry {
+    /// Pick up the revocations saved in `file`, if any
+    pub fn load(file: Option<PathBuf>) -> io::Result<InviteRegistry> {
+        let mut registry = InviteRegistry { file, ..InviteRegistry::default() };
+        if let Some(path) = &registry.file {
+            match fs::read(path) {
+                Ok(bytes) => registry.revoked = serde_json::from_slice(&bytes)?,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        registry.prune();
+        Ok(registry)
+    }
+
+    pub fn record(&mut self, invite: IssuedInvite) {
+        self.issued.push(invite);
+        let now = Utc::now().timestamp();
+        // Live invites are always kept so they can still be revoked
+        while self.issued.len() > INVITES_KEPT {
+            let Some(gone) = self.issued.iter().position(|i| i.claims.expires_at <= now) else { break };
+            self.issued.remove(gone);
+        }
+    }
+
+    pub fn is_revoked(&self, id: &Uuid) -> bool {
+        self.revoked.contains_key(id)
+    }
+
+    /// Revoke any invite id, including ones minted before a restart, and save
+    /// the revocation. It takes effect even if saving fails.
+    pub fn revoke(&mut self, id: Uuid) -> io::Result<()> {
+        // An id we didn't mint could be on a token that lasts the longest allowed
+        let until = match self.issued.iter().find(|i| i.claims.id == id) {
+            Some(invite) => invite.claims.expires_at,
+            None => Utc::now().timestamp() + MAX_INVITE_SECS as i64,
+        };
+        self.revoked.insert(id, until);
+        self.prune();
+        self.save()
+    }
+
+    /// Forget revocations of tokens that have expired anyway
+    fn prune(&mut self) {
+        let now = Utc::now().timestamp();
+        self.revoked.retain(|_, until| *until > now);
+    }
+
+    /// Write to a temporary file and rename it, so a crash never leaves half a file
+    fn save(&self) -> io::Result<()> {
+        let Some(path) = &self.file else { return Ok(()) };
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, serde_json::to_vec(&self.revoked)?)?;
+        fs::rename(&temp, path)
+    }
+
+    /// Newest first
+    pub fn list(&self) -> Vec<serde_json::Value> {
+        self.issued
+            .iter()
+            .rev()
+            .map(|i| i.to_json(self.is_revoked(&i.claims.id)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims(expires_in: i64) -> InviteClaims {
+        InviteClaims {
+            id: Uuid::new_v4(),
+            room: Uuid::new_v4(),
+            role: InviteRole::Member,
+            expires_at: Utc::now().timestamp() + expires_in,
+        }
+    }
+
+    #[test]
+    fn signed_tokens_verify_with_the_same_secret_only() {
+        let signer = InviteSigner::new(Some("secret"));
+        let claims = claims(60);
+        let token = signer.sign(&claims);
+        assert!(is_signed(&token));
+
+        let verified = signer.verify(&token).unwrap();
+        assert_eq!((verified.id, verified.room, verified.role), (claims.id, claims.room, claims.role));
+        assert!(InviteSigner::new(Some("other")).verify(&token).is_err());
+        assert!(InviteSigner::new(None).verify(&token).is_err());
+    }
+
+    #[test]
+    fn tampered_or_malformed_tokens_are_rejected() {
+        let signer = InviteSigner::new(Some("secret"));
+        let token = signer.sign(&claims(60));
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = format!("{}.{}", BASE64URL.encode(serde_json::to_vec(&claims(3600)).unwrap()), signature);
+
+        assert_eq!(signer.verify(&forged).unwrap_err(), "invalid invite signature");
+        assert_eq!(signer.verify("no-dot").unwrap_err(), "malformed invite");
+        assert_eq!(signer.verify("a.!!!").unwrap_err(), "malformed invite");
+    }
+
+    #[test]
+    fn expired_tokens_are_rejected() {
+        let signer = InviteSigner::new(Some("secret"));
+        assert_eq!(signer.verify(&signer.sign(&claims(-1))).unwrap_err(), "invite has expired");
+    }
+
+    #[test]
+    fn revocations_survive_a_reload() {
+        let file = std::env::temp_dir().join(format!("revoked-{}.json", Uuid::new_v4()));
+        let mut registry = InviteRegistry::load(Some(file.clone())).unwrap();
+        let issued = claims(60);
+        let id = issued.id;
+        registry.record(IssuedInvite { claims: issued, created_by: None, created_at: Utc::now() });
+        let unknown = Uuid::new_v4();
+        registry.revoke(id).unwrap();
+        registry.revoke(unknown).unwrap();
+
+        let reloaded = InviteRegistry::load(Some(file.clone())).unwrap();
+        assert!(reloaded.is_revoked(&id));
+        assert!(reloaded.is_revoked(&unknown));
+        assert!(!reloaded.is_revoked(&Uuid::new_v4()));
+        fs::remove_file(file).unwrap();
+    }
+
+    #[test]
+    fn expired_revocations_are_pruned() {
+        let mut registry = InviteRegistry::default();
+        let issued = claims(-1);
+        let id = issued.id;
+        registry.record(IssuedInvite { claims: issued, created_by: None, created_at: Utc::now() });
+        registry.revoke(id).unwrap();
+        assert!(!registry.is_revoked(&id));
+    }
+
+    #[test]
+    fn unreadable_revocations_fail_the_load() {
+        let file = std::env::temp_dir().join(format!("revoked-{}.json", Uuid::new_v4()));
+        fs::write(&file, "not json").unwrap();
+        assert!(InviteRegistry::load(Some(file.clone())).is_err());
+        fs::remove_file(file).unwrap();
+    }
+}
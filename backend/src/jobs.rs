@@ -0,0 +1,365 @@
+use crate::exec::Stream;
+use crate::message::OutputEncoding;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Bytes of recent output kept in memory per job for `jobs` listings and late attaches
+const TAIL_BYTES: usize = 8 * 1024;
+/// Finished jobs kept per session (or per user, for detached jobs) before the oldest are forgotten
+const FINISHED_JOBS_KEPT: usize = 20;
+
+/// Where background jobs spool their full output
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct JobLogConfig {
+    pub log_dir: PathBuf,
+    /// Size at which a job's log is rotated
+    pub max_log_bytes: u64,
+    /// Log files kept per job, counting the live one; older output is dropped
+    pub max_log_files: usize,
+}
+
+impl Default for JobLogConfig {
+    fn default() -> Self {
+        JobLogConfig {
+            log_dir: std::env::temp_dir().join("http-ssh-jobs"),
+            max_log_bytes: 8 * 1024 * 1024,
+            max_log_files: 4,
+        }
+    }
+}
+
+impl JobLogConfig {
+    /// Create the log directory and clear logs left by a previous run, whose
+    /// jobs are gone and whose ids are about to be reused
+    pub fn prepare_dir(&self) -> io::Result<()> {
+        fs::create_dir_all(&self.log_dir)?;
+        for entry in fs::read_dir(&self.log_dir)? {
+            let entry = entry?;
+            if entry.file_name().to_string_lossy().starts_with("job-") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn log_path(&self, job_id: u32) -> PathBuf {
+        self.log_dir.join(format!("job-{}.log", job_id))
+    }
+}
+
+/// Appends a job's output to its log file, rotating it to `.1`, `.2`, ... as it grows
+pub struct JobLog {
+    path: PathBuf,
+    file: File,
+    written: u64,
+    max_bytes: u64,
+    max_files: usize,
+}
+
+impl JobLog {
+    pub fn create(path: PathBuf, config: &JobLogConfig) -> io::Result<JobLog> {
+        let file = File::create(&path)?;
+        Ok(JobLog {
+            path,
+            file,
+            written: 0,
+            max_bytes: config.max_log_bytes.max(1),
+            max_files: config.max_log_files.max(1),
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.written > 0 && self.written + data.len() as u64 > self.max_bytes {
+            self.rotate()?;
+        }
+        self.file.write_all(data)?;
+        self.written += data.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // The oldest file falls off the end, everything else moves down one
+        for n in (1..self.max_files).rev() {
+            let from = if n == 1 { self.path.clone() } else { rotated_path(&self.path, n - 1) };
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, n))?;
+            }
+        }
+        self.file = File::create(&self.path)?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// A job's log files that still exist, oldest first
+pub fn log_files(path: &Path, max_files: usize) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = (1..max_files.max(1)).rev().map(|n| rotated_path(path, n)).collect();
+    files.push(path.to_path_buf());
+    files.retain(|f| f.exists());
+    files
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobStatus {
+    Running,
+    Exited,
+    Killed,
+    TimedOut,
+    Failed,
+}
+
+impl JobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobStatus::Running => "running",
+            JobStatus::Exited => "exited",
+            JobStatus::Killed => "killed",
+            JobStatus::TimedOut => "timedOut",
+            JobStatus::Failed => "failed",
+        }
+    }
+}
+
+/// A background command. Ordinary jobs belong to the session that started
+/// them; detached jobs belong to their user and outlive the session.
+pub struct Job {
+    pub id: u32,
+    pub request_id: u32,
+    pub command: String,
+    pub pid: u32,
+    /// Session that started the job, or last attached to it
+    pub session: Uuid,
+    pub owner: Option<String>,
+    pub detached: bool,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub status: JobStatus,
+    pub exit_code: Option<i32>,
+    /// Whether `session` wants live `job_output`, and how
+    pub attached: bool,
+    pub attach_encoding: OutputEncoding,
+    /// Set once `job_kill` has been sent, so the exit reads as killed
+    pub kill_requested: bool,
+    /// Full output on disk, if the log could be created
+    pub log_path: Option<PathBuf>,
+    pub output_bytes: u64,
+    /// Output chunks so far; each one's number is its binary frame's sequence,
+    /// so a client that re-attaches can tell what it missed
+    chunks: u32,
+    tail: VecDeque<(u32, Stream, Vec<u8>)>,
+    tail_len: usize,
+}
+
+impl Job {
+    /// Keep a chunk of output in the tail; returns its sequence number
+    pub fn record_output(&mut self, stream: Stream, data: &[u8]) -> u32 {
+        let sequence = self.chunks;
+        self.chunks = self.chunks.wrapping_add(1);
+        self.output_bytes += data.len() as u64;
+        let data = &data[data.len().saturating_sub(TAIL_BYTES)..];
+        self.tail_len += data.len();
+        self.tail.push_back((sequence, stream, data.to_vec()));
+
+        while self.tail_len > TAIL_BYTES {
+            let Some((_, _, front)) = self.tail.front_mut() else { break };
+            let excess = self.tail_len - TAIL_BYTES;
+            if front.len() <= excess {
+                self.tail_len -= front.len();
+                self.tail.pop_front();
+            } else {
+                front.drain(..excess);
+                self.tail_len -= excess;
+            }
+        }
+        sequence
+    }
+
+    /// Recent output in the order it was written, with each chunk's sequence number
+    pub fn tail(&self) -> impl Iterator<Item = &(u32, Stream, Vec<u8>)> {
+        self.tail.iter()
+    }
+
+    pub fn tail_text(&self) -> String {
+        let bytes: Vec<u8> = self.tail.iter().flat_map(|(_, _, b)| b.iter().copied()).collect();
+        String::from_utf8_lossy(&bytes).to_string()
+    }
+
+    /// Whether a session (signed in as `user`) may see and control this job
+    pub fn visible_to(&self, session: &Uuid, user: Option<&str>) -> bool {
+        self.session == *session || (self.detached && user.is_some() && self.owner.as_deref() == user)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "jobId": self.id,
+            "requestId": self.request_id,
+            "command": self.command,
+            "pid": self.pid,
+            "detached": self.detached,
+            "status": self.status.as_str(),
+            "exitCode": self.exit_code,
+            "startedAt": self.started_at.to_rfc3339(),
+            "finishedAt": self.finished_at.map(|t| t.to_rfc3339()),
+            "attached": self.attached,
+            "outputBytes": self.output_bytes,
+            "hasLog": self.log_path.is_some(),
+            "tail": self.tail_text()
+        })
+    }
+
+    /// Jobs that share a retention limit: a session's, or a user's detached ones
+    fn retention_group(&self) -> (Option<String>, Option<Uuid>) {
+        if self.detached {
+            (self.owner.clone(), None)
+        } else {
+            (None, Some(self.session))
+        }
+    }
+}
+
+/// Details of a newly spawned background job
+pub struct NewJob<'a> {
+    pub session: Uuid,
+    pub owner: Option<String>,
+    pub detached: bool,
+    pub request_id: u32,
+    pub command: &'a str,
+    pub pid: u32,
+    pub log_path: Option<PathBuf>,
+}
+
+/// Every background job on the server, by id
+#[derive(Default)]
+pub struct JobRegistry {
+    next_id: u32,
+    jobs: BTreeMap<u32, Job>,
+    max_log_files: usize,
+}
+
+impl JobRegistry {
+    pub fn new(config: &JobLogConfig) -> JobRegistry {
+        JobRegistry {
+            max_log_files: config.max_log_files,
+            ..Default::default()
+        }
+    }
+
+    /// Id the next started job will get, so its log can be opened before it is registered
+    pub fn next_id(&self) -> u32 {
+        self.next_id + 1
+    }
+
+    /// Register a freshly spawned job and hand back its id
+    pub fn start(&mut self, new: NewJob) -> u32 {
+        self.next_id += 1;
+        let id = self.next_id;
+
+        self.jobs.insert(id, Job {
+            id,
+            request_id: new.request_id,
+            command: new.command.to_string(),
+            pid: new.pid,
+            session: new.session,
+            owner: new.owner,
+            detached: new.detached,
+            started_at: Utc::now(),
+            finished_at: None,
+            status: JobStatus::Running,
+            exit_code: None,
+            attached: false,
+            attach_encoding: OutputEncoding::Text,
+            kill_requested: false,
+            log_path: new.log_path,
+            output_bytes: 0,
+            chunks: 0,
+            tail: VecDeque::new(),
+            tail_len: 0,
+        });
+        id
+    }
+
+    pub fn get(&self, job_id: u32) -> Option<&Job> {
+        self.jobs.get(&job_id)
+    }
+
+    pub fn get_mut(&mut self, job_id: u32) -> Option<&mut Job> {
+        self.jobs.get_mut(&job_id)
+    }
+
+    /// A job, if the given session may see it
+    pub fn visible(&mut self, job_id: u32, session: &Uuid, user: Option<&str>) -> Option<&mut Job> {
+        self.jobs.get_mut(&job_id).filter(|j| j.visible_to(session, user))
+    }
+
+    pub fn list(&self, session: &Uuid, user: Option<&str>) -> Vec<&Job> {
+        self.jobs.values().filter(|j| j.visible_to(session, user)).collect()
+    }
+
+    /// Process ids of every job still running
+    pub fn running_pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.jobs.values().filter(|j| j.status == JobStatus::Running).map(|j| j.pid)
+    }
+
+    /// A job's log files, oldest first
+    pub fn log_files(&self, job: &Job) -> Vec<PathBuf> {
+        job.log_path.as_deref().map(|p| log_files(p, self.max_log_files)).unwrap_or_default()
+    }
+
+    /// Mark a job done and forget the oldest finished ones past the limit
+    pub fn finish(&mut self, job_id: u32, status: JobStatus, exit_code: Option<i32>) -> Option<&Job> {
+        let job = self.jobs.get_mut(&job_id)?;
+        job.status = if job.kill_requested && status != JobStatus::TimedOut { JobStatus::Killed } else { status };
+        job.exit_code = exit_code;
+        job.finished_at = Some(Utc::now());
+
+        let group = job.retention_group();
+        let finished: Vec<u32> = self
+            .jobs
+            .values()
+            .filter(|j| j.status != JobStatus::Running && j.id != job_id && j.retention_group() == group)
+            .map(|j| j.id)
+            .collect();
+        for old in finished.iter().take(finished.len().saturating_sub(FINISHED_JOBS_KEPT)) {
+            self.forget(*old);
+        }
+
+        self.jobs.get(&job_id)
+    }
+
+    /// Drop a session's own jobs; detached jobs stay for their user to pick up
+    pub fn remove_session(&mut self, session: &Uuid) {
+        let owned: Vec<u32> = self
+            .jobs
+            .values()
+            .filter(|j| j.session == *session && !j.detached)
+            .map(|j| j.id)
+            .collect();
+        for id in owned {
+            self.forget(id);
+        }
+
+        for job in self.jobs.values_mut().filter(|j| j.session == *session) {
+            job.attached = false;
+        }
+    }
+
+    fn forget(&mut self, job_id: u32) {
+        if let Some(job) = self.jobs.remove(&job_id) {
+            for file in self.log_files(&job) {
+                let _ = fs::remove_file(file);
+            }
+        }
+    }
+}
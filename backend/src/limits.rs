@@ -0,0 +1,226 @@
+use serde::Deserialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use uuid::Uuid;
+
+/// Resource caps for executed commands. `None` leaves the limit unset.
+/// The rlimits apply to each command (`processes` is counted per uid by the
+/// kernel); `memoryMaxBytes`/`pidsMax` apply to the whole session through
+/// its cgroup, when cgroup v2 is available.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct ResourceLimits {
+    pub cpu_secs: Option<u64>,
+    pub address_space_bytes: Option<u64>,
+    pub open_files: Option<u64>,
+    pub processes: Option<u64>,
+    pub file_size_bytes: Option<u64>,
+    pub memory_max_bytes: Option<u64>,
+    pub pids_max: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Layer `overrides` (e.g. a user's profile) on top of these limits
+    pub fn merged(&self, overrides: &ResourceLimits) -> ResourceLimits {
+        ResourceLimits {
+            cpu_secs: overrides.cpu_secs.or(self.cpu_secs),
+            address_space_bytes: overrides.address_space_bytes.or(self.address_space_bytes),
+            open_files: overrides.open_files.or(self.open_files),
+            processes: overrides.processes.or(self.processes),
+            file_size_bytes: overrides.file_size_bytes.or(self.file_size_bytes),
+            memory_max_bytes: overrides.memory_max_bytes.or(self.memory_max_bytes),
+            pids_max: overrides.pids_max.or(self.pids_max),
+        }
+    }
+
+    /// Have the child set its rlimits between fork and exec
+    #[cfg(unix)]
+    pub fn apply(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        if self.cpu_secs.is_none()
+            && self.address_space_bytes.is_none()
+            && self.open_files.is_none()
+            && self.processes.is_none()
+            && self.file_size_bytes.is_none()
+        {
+            return;
+        }
+
+        // The resource type differs between libcs, so let the macro infer it
+        macro_rules! set_rlimit {
+            ($resource:expr, $soft:expr, $hard:expr) => {{
+                let limit = libc::rlimit {
+                    rlim_cur: $soft as libc::rlim_t,
+                    rlim_max: $hard as libc::rlim_t,
+                };
+                if libc::setrlimit($resource, &limit) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }};
+        }
+
+        let limits = self.clone();
+        // Only async-signal-safe calls are allowed in here
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(secs) = limits.cpu_secs {
+                    // Soft limit raises SIGXCPU so we can tell why it died; hard limit backs it up
+                    set_rlimit!(libc::RLIMIT_CPU, secs, secs + 1);
+                }
+                if let Some(bytes) = limits.address_space_bytes {
+                    set_rlimit!(libc::RLIMIT_AS, bytes, bytes);
+                }
+                if let Some(n) = limits.open_files {
+                    set_rlimit!(libc::RLIMIT_NOFILE, n, n);
+                }
+                if let Some(n) = limits.processes {
+                    set_rlimit!(libc::RLIMIT_NPROC, n, n);
+                }
+                if let Some(bytes) = limits.file_size_bytes {
+                    set_rlimit!(libc::RLIMIT_FSIZE, bytes, bytes);
+                }
+                Ok(())
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _cmd: &mut Command) {}
+}
+
+/// Which limit, if any, ended a command
+pub fn limit_hit(status: &ExitStatus, before: &CgroupEvents, after: &CgroupEvents) -> Option<&'static str> {
+    if after.oom_kills > before.oom_kills {
+        return Some("memory");
+    }
+    if after.pids_max_hits > before.pids_max_hits {
+        return Some("pids");
+    }
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        // A shell reports a child killed by signal N as exit code 128 + N
+        let signal = status.signal().or_else(|| status.code().filter(|c| *c > 128).map(|c| c - 128));
+        match signal {
+            Some(libc::SIGXCPU) => return Some("cpu"),
+            Some(libc::SIGXFSZ) => return Some("fileSize"),
+            _ => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = status;
+
+    None
+}
+
+/// Counters from a cgroup's `memory.events` and `pids.events`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgroupEvents {
+    pub oom_kills: u64,
+    pub pids_max_hits: u64,
+}
+
+/// A cgroup v2 directory holding every process a session starts
+pub struct SessionCgroup {
+    path: PathBuf,
+}
+
+impl SessionCgroup {
+    /// Check that `root` sits on a cgroup v2 hierarchy and delegate the
+    /// memory and pids controllers to the session cgroups below it.
+    pub fn prepare_root(root: &Path) -> io::Result<()> {
+        let parent = root.parent().unwrap_or(root);
+        if !parent.join("cgroup.controllers").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} is not on a cgroup v2 hierarchy", parent.display()),
+            ));
+        }
+
+        fs::create_dir_all(root)?;
+        fs::write(root.join("cgroup.subtree_control"), "+memory +pids")
+    }
+
+    pub fn create(root: &Path, session: &Uuid, limits: &ResourceLimits) -> io::Result<SessionCgroup> {
+        let path = root.join(format!("session-{}", session));
+        fs::create_dir_all(&path)?;
+
+        let cgroup = SessionCgroup { path };
+        if let Some(bytes) = limits.memory_max_bytes {
+            fs::write(cgroup.path.join("memory.max"), bytes.to_string())?;
+        }
+        if let Some(n) = limits.pids_max {
+            fs::write(cgroup.path.join("pids.max"), n.to_string())?;
+        }
+        Ok(cgroup)
+    }
+
+    /// Move the child into this cgroup before it execs, so nothing it forks escapes
+    #[cfg(unix)]
+    pub fn attach(&self, cmd: &mut Command) {
+        use std::os::unix::process::CommandExt;
+
+        let Ok(procs) = std::ffi::CString::new(self.path.join("cgroup.procs").to_string_lossy().as_bytes()) else {
+            return;
+        };
+
+        unsafe {
+            cmd.pre_exec(move || {
+                // Writing "0" to cgroup.procs moves the writing process itself
+                let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let written = libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1);
+                let result = if written == 1 { Ok(()) } else { Err(io::Error::last_os_error()) };
+                libc::close(fd);
+                result
+            });
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn attach(&self, _cmd: &mut Command) {}
+
+    pub fn events(&self) -> CgroupEvents {
+        let counter = |file: &str, key: &str| -> u64 {
+            fs::read_to_string(self.path.join(file))
+                .ok()
+                .and_then(|contents| {
+                    contents.lines().find_map(|line| {
+                        let (k, v) = line.split_once(' ')?;
+                        if k == key { v.trim().parse().ok() } else { None }
+                    })
+                })
+                .unwrap_or(0)
+        };
+
+        CgroupEvents {
+            oom_kills: counter("memory.events", "oom_kill"),
+            pids_max_hits: counter("pids.events", "max"),
+        }
+    }
+
+    /// Kill whatever is still running in the cgroup and remove it. Blocks for up
+    /// to 200ms while the kernel reaps, so keep it off actor threads.
+    pub fn remove(self) {
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+
+        // The kernel refuses rmdir until the killed processes are reaped
+        let mut result = fs::remove_dir(&self.path);
+        for _ in 0..10 {
+            if result.is_ok() {
+                return;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            result = fs::remove_dir(&self.path);
+        }
+        if let Err(e) = result {
+            tracing::warn!(path = %self.path.display(), error = %e, "failed to remove cgroup");
+        }
+    }
+}
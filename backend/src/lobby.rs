@@ -1,232 +1,1539 @@
-use crate::message::{ClientActorMessage, Connect, Disconnect, WsMessage};
-use actix::prelude::{Actor, Context, Handler, Recipient};
+use crate::chat::{self, ChatLog, ChatMessage};
+use crate::config::{ServerConfig, SessionProfile};
+use crate::invite::{self, InviteClaims, InviteRegistry, InviteSigner, IssuedInvite};
+use crate::fanout::{self, FanoutRegistry, FanoutTarget, TargetResult};
+use crate::exec::{self, Captured, CommandResult, ExitKind, ExitReport, OnOutputLimit, PendingCommand, Stream};
+use crate::jobs::{Job, JobLog, JobRegistry, JobStatus, NewJob};
+use crate::limits::{self, SessionCgroup};
+use crate::room::{RoomDirectory, RoomError, SharedRoom};
+use crate::schedule::{RunStatus, ScheduleRun, Scheduler};
+use crate::message::{
+    Admission, ClientActorMessage, CloseSession, CommandFinished, CommandRequest, Connect, Disconnect, ExecCgroup,
+    ExecFinished, ExecList, ExecStarted, FanoutFinished,
+    FanoutRequest, InviteCreate, InviteList, InviteRevoke, JobLogFiles, JobOutput, JobRequest, OutputEncoding,
+    RoomClose, RoomCreate, RoomInspect, RoomJoin, RoomList, ScheduleAdd, ScheduleFinished, ScheduleRef,
+    HealthProbe, HealthReport, RefreshGauges, SessionDisconnect, SessionInspect, SessionKill, SessionList, Shutdown, WsBinaryMessage, WsMessage,
+};
+use crate::metrics::METRICS;
+use crate::shutdown;
+use tracing::Span;
+use crate::shell::{CwdReport, CwdTracking};
+use actix::prelude::{Actor, Addr, AsyncContext, Context, Handler, MessageResult, Recipient, SpawnHandle};
+use actix_web_actors::ws::CloseCode;
+use chrono::{DateTime, Utc};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use std::collections::{HashMap, HashSet};
-use std::process::Command;
+use std::sync::Arc;
 use uuid::Uuid;
-use serde_json;
-use std::path::PathBuf;
-use std::process::Stdio;
-use std::time::Duration;
-use wait_timeout::ChildExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 type Socket = Recipient<WsMessage>;
 
+/// How often the lobby looks for scheduled commands that are due
+const SCHEDULE_TICK: Duration = Duration::from_secs(1);
+/// How often the lobby closes rooms that have sat empty too long, and
+/// sessions that are idle, too old or whose invite has run out
+const ROOM_SWEEP: Duration = Duration::from_secs(10);
+
+/// How often a shutting-down lobby checks whether everything has exited
+const DRAIN_TICK: Duration = Duration::from_millis(200);
+
+/// Requests a read-only session may not make
+const READ_ONLY_DENIED: &[&str] = &[
+    "command",
+    "fanout",
+    "job_kill",
+    "control_request",
+    "control_takeover",
+    "schedule_add",
+    "schedule_remove",
+];
+
+/// Progress of a graceful shutdown
+struct Draining {
+    deadline: Instant,
+    /// When SIGTERM went out, once the deadline has passed
+    termed_at: Option<Instant>,
+    killed_at: Option<Instant>,
+    tick: SpawnHandle,
+    done: Option<tokio::sync::oneshot::Sender<()>>,
+}
+
+/// A command whose worker thread hasn't reported back yet
+struct RunningCommand {
+    /// Assigned by the server; clients may reuse a `request_id`
+    id: u32,
+    request_id: u32,
+    pid: u32,
+    detached: bool,
+    command: String,
+    started_at: DateTime<Utc>,
+}
+
+impl RunningCommand {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "commandId": self.id,
+            "requestId": self.request_id,
+            "pid": self.pid,
+            "command": self.command,
+            "detached": self.detached,
+            "startedAt": self.started_at.to_rfc3339()
+        })
+    }
+}
+
 pub struct Lobby {
+    config: Arc<ServerConfig>,
+    cgroup_root: Option<PathBuf>, // Set only when cgroup v2 is usable
+    next_request_id: u32,
     sessions: HashMap<Uuid, Socket>,
-    rooms: HashMap<Uuid, HashSet<Uuid>>,
-    curr_dir: HashMap<Uuid, PathBuf>, // Per-session current directory
+    binary_sockets: HashMap<Uuid, Recipient<WsBinaryMessage>>,
+    close_sockets: HashMap<Uuid, Recipient<CloseSession>>,
+    directory: RoomDirectory,                // Rooms that may be joined
+    invite_signer: InviteSigner,
+    invites: InviteRegistry,                 // Signed invites handed out, and revoked
+    rooms: HashMap<Uuid, HashSet<Uuid>>,     // Members of each occupied room
+    session_rooms: HashMap<Uuid, Uuid>,     // Room each session is in
+    connected_at: HashMap<Uuid, DateTime<Utc>>, // When each session joined
+    last_active: HashMap<Uuid, DateTime<Utc>>,  // Last message from each session
+    expiry_warned: HashMap<Uuid, DateTime<Utc>>, // Deadline each session was last warned about
+    shared_rooms: HashMap<Uuid, SharedRoom>, // Rooms whose members share one shell
+    // Shell state below is per session, or per room for shared rooms (see `shell_key`)
+    curr_dir: HashMap<Uuid, PathBuf>,       // Current directory
+    prev_dir: HashMap<Uuid, PathBuf>,       // `$OLDPWD`, for `cd -`
+    dir_stack: HashMap<Uuid, Vec<PathBuf>>, // `pushd` stack, below the current directory
+    profiles: HashMap<Uuid, SessionProfile>, // Per-session user, role, shell and limits
+    cgroups: HashMap<Uuid, SessionCgroup>,   // Per-session cgroup, when enabled
+    running: HashMap<Uuid, Vec<RunningCommand>>, // Per-session commands still executing
+    orphaned: HashMap<u32, RunningCommand>,  // Detached commands whose session has ended, by id
+    execs: HashMap<Uuid, (Option<String>, RunningCommand)>, // `POST /api/exec` commands, with their user
+    jobs: JobRegistry,                       // Background jobs, by job id
+    schedules: Scheduler,                    // Users' scheduled commands
+    fanouts: FanoutRegistry,                 // Fan-out commands still running somewhere
+    draining: Option<Draining>,              // Set once shutdown has begun
+    chats: HashMap<Uuid, ChatLog>,           // Recent chat, by room
+    next_chat_id: u64,
 }
 
-impl Default for Lobby {
-    fn default() -> Lobby {
-        Lobby {
+impl Lobby {
+    /// Fails only if saved invite revocations can't be read; starting without
+    /// them would let revoked invites back in
+    pub fn new(config: Arc<ServerConfig>) -> std::io::Result<Lobby> {
+        let cgroup_root = config.cgroup_root.clone().filter(|root| {
+            match SessionCgroup::prepare_root(root) {
+                Ok(()) => true,
+                Err(e) => {
+                    tracing::warn!(root = %root.display(), error = %e, "cgroup limits disabled");
+                    false
+                }
+            }
+        });
+
+        if let Err(e) = config.jobs.prepare_dir() {
+            tracing::warn!(dir = %config.jobs.log_dir.display(), error = %e, "job logs unavailable");
+        }
+        let jobs = JobRegistry::new(&config.jobs);
+        let invite_signer = InviteSigner::new(config.invite_secret.as_deref());
+        let invites = InviteRegistry::load(config.revoked_invites_file.clone()).map_err(|e| {
+            let file = config.revoked_invites_file.as_deref().unwrap_or(Path::new("")).display();
+            std::io::Error::new(e.kind(), format!("can't load revoked invites from {}: {}", file, e))
+        })?;
+
+        Ok(Lobby {
+            config,
+            cgroup_root,
+            next_request_id: 0,
             sessions: HashMap::new(),
+            binary_sockets: HashMap::new(),
+            close_sockets: HashMap::new(),
+            directory: RoomDirectory::default(),
+            invite_signer,
+            invites,
             rooms: HashMap::new(),
+            session_rooms: HashMap::new(),
+            connected_at: HashMap::new(),
+            last_active: HashMap::new(),
+            expiry_warned: HashMap::new(),
+            shared_rooms: HashMap::new(),
             curr_dir: HashMap::new(),
-        }
+            prev_dir: HashMap::new(),
+            dir_stack: HashMap::new(),
+            profiles: HashMap::new(),
+            cgroups: HashMap::new(),
+            running: HashMap::new(),
+            orphaned: HashMap::new(),
+            execs: HashMap::new(),
+            jobs,
+            schedules: Scheduler::default(),
+            fanouts: FanoutRegistry::default(),
+            draining: None,
+            chats: HashMap::new(),
+            next_chat_id: 0,
+        })
+    }
+
+    /// A fresh id for a request or running command
+    fn next_id(&mut self) -> u32 {
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+        self.next_request_id
     }
-}
 
-impl Lobby {
     fn send_message(&self, message: &str, id_to: &Uuid) {
         if let Some(socket_recipient) = self.sessions.get(id_to) {
-            let _ = socket_recipient.do_send(WsMessage {
+            socket_recipient.do_send(WsMessage {
                 message: message.to_owned(),
             });
         } else {
-            println!("attempting to send message but couldn't find user id: {}", id_to);
+            tracing::debug!(session_id = %id_to, "no socket for session, dropping message");
+        }
+    }
+
+    fn send_binary(&self, data: Vec<u8>, id_to: &Uuid) {
+        if let Some(socket_recipient) = self.binary_sockets.get(id_to) {
+            socket_recipient.do_send(WsBinaryMessage { data });
+        }
+    }
+
+    /// Key for a session's shell state: its shared room's id, or its own
+    fn shell_key(&self, session: &Uuid) -> Uuid {
+        match self.session_rooms.get(session) {
+            Some(room) if self.shared_rooms.contains_key(room) => *room,
+            _ => *session,
+        }
+    }
+
+    /// Sessions that see output sent to `id`: a shared room's members, or just the session
+    fn audience(&self, id: &Uuid) -> Vec<Uuid> {
+        if self.shared_rooms.contains_key(id) {
+            self.rooms.get(id).map(|members| members.iter().copied().collect()).unwrap_or_default()
+        } else {
+            vec![*id]
+        }
+    }
+
+    /// Send a result to a session, or to every member when `id_to` is a shared room
+    fn send_command_result(&self, id_to: &Uuid, result: &CommandResult, encoding: OutputEncoding) {
+        let audience = self.audience(id_to);
+        let current_dir = self.curr_dir.get(&self.shell_key(id_to)).cloned().unwrap_or_default();
+
+        let mut frames = 0u32;
+        let (stdout, stderr) = match encoding {
+            OutputEncoding::Text => {
+                // Server notes ride along in stderr, as older clients expect
+                let mut stderr = String::from_utf8_lossy(&result.stderr).to_string();
+                for note in &result.notes {
+                    if !stderr.is_empty() && !stderr.ends_with('\n') {
+                        stderr.push('\n');
+                    }
+                    stderr.push_str(note);
+                }
+                (String::from_utf8_lossy(&result.stdout).to_string(), stderr)
+            }
+            OutputEncoding::Base64 => (BASE64.encode(&result.stdout), BASE64.encode(&result.stderr)),
+            OutputEncoding::Binary => {
+                for (stream, bytes) in [(Stream::Stdout, &result.stdout), (Stream::Stderr, &result.stderr)] {
+                    for chunk in bytes.chunks(exec::FRAME_DATA_MAX) {
+                        let frame = exec::output_frame(result.request_id, stream, frames, chunk);
+                        for session in &audience {
+                            self.send_binary(frame.clone(), session);
+                        }
+                        frames += 1;
+                    }
+                }
+                (String::new(), String::new())
+            }
+        };
+
+        let response = serde_json::json!({
+            "type": "command_output",
+            "payload": {
+                "requestId": result.request_id,
+                "command": result.command,
+                "runBy": { "sessionId": result.session, "user": result.user },
+                "encoding": encoding,
+                "stdout": stdout,
+                "stderr": stderr,
+                "frames": frames,
+                "exitCode": result.exit_code,
+                "exit": result.exit.to_json(),
+                "currentDirectory": current_dir.to_string_lossy(),
+                "limitExceeded": result.limit_exceeded,
+                "truncated": result.truncated,
+                "stdoutBytes": result.stdout_bytes,
+                "stderrBytes": result.stderr_bytes,
+                "notes": result.notes
+            }
+        });
+        for session in &audience {
+            self.send_message(&response.to_string(), session);
+        }
+    }
+
+    /// Record a directory change, keeping the previous one for `cd -`
+    fn set_dir(&mut self, id: &Uuid, new_dir: PathBuf) {
+        if let Some(old) = self.curr_dir.insert(*id, new_dir.clone()) {
+            if old != new_dir {
+                self.prev_dir.insert(*id, old);
+            }
+        }
+    }
+
+    /// `cd` for shells that can't report their own working directory
+    fn emulate_cd(&mut self, result: &mut CommandResult, target_path: &str, curr_dir: &Path, id_to: &Uuid) {
+        let home = || -> PathBuf {
+            std::env::var("HOME")
+                .or_else(|_| std::env::var("USERPROFILE"))
+                .unwrap_or_else(|_| "/".to_string()).into()
+        };
+
+        let new_path = if target_path.is_empty() || target_path == "~" {
+            // cd with no arguments goes to home directory
+            home()
+        } else if let Some(rest) = target_path.strip_prefix("~/") {
+            home().join(rest)
+        } else if target_path == "-" {
+            // cd - goes back to the previous directory
+            match self.prev_dir.get(id_to) {
+                Some(prev) => prev.clone(),
+                None => {
+                    result.stderr = b"cd: OLDPWD not set".to_vec();
+                    result.exit_code = 1;
+                    return;
+                }
+            }
+        } else if target_path.starts_with('/') || (cfg!(windows) && target_path.len() > 1 && target_path.chars().nth(1) == Some(':')) {
+            // Absolute path (Unix or Windows)
+            PathBuf::from(target_path)
+        } else {
+            // Relative path, including `.` and `..`
+            curr_dir.join(target_path)
+        };
+
+        match new_path.canonicalize() {
+            Ok(resolved) => {
+                // Like the shells, `cd -` prints where it landed
+                if target_path == "-" {
+                    result.stdout = format!("{}\n", resolved.to_string_lossy()).into_bytes();
+                }
+                result.exit_code = 0;
+                self.set_dir(id_to, resolved);
+            }
+            Err(e) => {
+                result.stderr = format!("cd: \"{}\": {}\n", target_path, e).into_bytes();
+                result.exit_code = 1;
+            }
         }
     }
 
-    /// Execute command with support for `cd` + timeout
-    fn execute_command(&mut self, command: &str, id_to: &Uuid) {
+    /// Pick up the directory state a tracked shell reported on exit
+    fn apply_cwd_report(&mut self, id: &Uuid, state_file: &Path) {
+        if let Some(report) = CwdReport::take(state_file) {
+            self.set_dir(id, report.cwd);
+            if let Some(oldpwd) = report.oldpwd {
+                self.prev_dir.insert(*id, oldpwd);
+            }
+            self.dir_stack.insert(*id, report.dir_stack);
+        }
+    }
+
+    /// Start a command on a worker thread, with working-directory tracking + timeout.
+    /// The worker reports back through `CommandFinished`.
+    fn execute_command(&mut self, request: &CommandRequest, id_to: &Uuid, lobby: Addr<Lobby>) {
+        let command = request.command.as_str();
+        let request_id = request.request_id.unwrap_or_else(|| self.next_id());
+        let _span = tracing::info_span!("command", request_id).entered();
+        let mut result = CommandResult::new(request_id, command);
+        let key = self.shell_key(id_to);
+
         // Get or initialize current directory for this session
-        let curr_dir = self.curr_dir.get(id_to)
+        let curr_dir = self.curr_dir.get(&key)
             .cloned()
             .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")));
 
-        // Handle `cd` separately
-        if command.starts_with("cd ") {
-            let target_path = command[3..].trim();
-            let new_path = if target_path.is_empty() {
-                // cd with no arguments goes to home directory
-                std::env::var("HOME")
-                    .or_else(|_| std::env::var("USERPROFILE"))
-                    .unwrap_or_else(|_| "/".to_string()).into()
-            } else if target_path == "." {
-                // cd . stays in current directory
-                curr_dir.clone()
-            } else if target_path == ".." {
-                // cd .. goes to parent directory
-                curr_dir.parent().unwrap_or(&curr_dir).to_path_buf()
-            } else if target_path.starts_with('/') || (cfg!(windows) && target_path.len() > 1 && target_path.chars().nth(1) == Some(':')) {
-                // Absolute path (Unix or Windows)
-                PathBuf::from(target_path)
-            } else {
-                // Relative path
-                curr_dir.join(target_path)
+        let Some(profile) = self.profiles.get(id_to).cloned() else {
+            tracing::warn!(session_id = %id_to, "no profile registered for session");
+            return;
+        };
+        if self.shared_rooms.get(&key).is_some_and(|room| !room.can_type(id_to)) {
+            self.send_error(id_to, "command", "you are watching this room; send control_request to drive");
+            return;
+        }
+        result.session = *id_to;
+        result.user = profile.user.clone();
+        // In a shared room everyone drives the room's shell, with their own limits
+        let shell = self.shared_rooms.get(&key).map_or(&profile.shell, |room| &room.shell).clone();
+        let shell = &shell;
+        let tracking = shell.cwd_tracking();
+
+        let policy = self.config.role(&profile.role);
+        let timeout = if request.detached {
+            if !policy.allow_detached {
+                result.add_note(format!("Role '{}' may not run detached commands", profile.role));
+                self.send_command_result(id_to, &result, request.output_encoding);
+                return;
+            }
+            // A detached job is found again by its owner, so it needs one
+            if request.background && profile.user.is_none() {
+                self.send_error(id_to, "command", "detached jobs need a signed-in user");
+                return;
+            }
+            None
+        } else {
+            // Background jobs are for long work, so they default to the longest allowed
+            let default_secs = if request.background { policy.max_timeout_secs } else { self.config.command_timeout_secs };
+            let secs = request.timeout_secs.unwrap_or(default_secs);
+            if secs == 0 {
+                result.add_note("timeoutSecs must be at least 1; use detached for no timeout".to_string());
+                self.send_command_result(id_to, &result, request.output_encoding);
+                return;
+            }
+            Some(Duration::from_secs(secs.min(policy.max_timeout_secs)))
+        };
+
+        // Shells we can't query get our own `cd` handling
+        if tracking == CwdTracking::Emulated && !request.background {
+            let trimmed = command.trim();
+            if trimmed == "cd" || trimmed.starts_with("cd ") {
+                self.emulate_cd(&mut result, trimmed[2..].trim(), &curr_dir, &key);
+                result.exit = ExitReport::exited(result.exit_code);
+                if let Some(room) = self.shared_rooms.get_mut(&key) {
+                    room.record_start(request_id, command, *id_to, result.user.clone());
+                    room.record_finish(request_id, result.exit_code);
+                }
+                self.send_command_result(&key, &result, request.output_encoding);
+                return;
+            }
+        }
+
+        let mut state_file = None;
+        // Like `cd dir &`, a background job can't move the session
+        let mut process_cmd = match tracking {
+            CwdTracking::Reported { .. } if !request.background => {
+                let file = std::env::temp_dir().join(format!("http-ssh-{}.cwd", Uuid::new_v4()));
+                let dir_stack = self.dir_stack.get(&key).cloned().unwrap_or_default();
+                let mut cmd = shell.command(&shell.wrap_tracked(command, &dir_stack, &file));
+                if let Some(prev) = self.prev_dir.get(&key) {
+                    cmd.env("OLDPWD", prev);
+                }
+                state_file = Some(file);
+                cmd
+            }
+            _ => shell.command(command),
+        };
+
+        // Detached commands outlive the session, and with it the session's cgroup
+        let cgroup = self.cgroups.get(id_to).filter(|_| !request.detached);
+        if let Some(cgroup) = cgroup {
+            cgroup.attach(&mut process_cmd);
+        }
+        process_cmd.envs(&profile.env);
+        profile.limits.apply(&mut process_cmd);
+        let events_before = cgroup.map(|c| c.events()).unwrap_or_default();
+
+        exec::prepare(&mut process_cmd);
+
+        // Spawn process (non-blocking)
+        let process = match process_cmd.current_dir(&curr_dir).spawn() {
+            Ok(process) => process,
+            Err(e) => {
+                result.exit = ExitReport::spawn_failed();
+                METRICS.record_exit(if request.background { "job" } else { "command" }, &result.exit);
+                result.add_note(format!("Failed to execute command: {}", e));
+                self.send_command_result(id_to, &result, request.output_encoding);
+                return;
+            }
+        };
+
+        let running_id = self.next_id();
+        self.running.entry(*id_to).or_default().push(RunningCommand {
+            id: running_id,
+            request_id,
+            pid: process.id(),
+            detached: request.detached,
+            command: command.to_string(),
+            started_at: Utc::now(),
+        });
+
+        let session = *id_to;
+        let mut job_log = None;
+        let (max_bytes, on_limit, job_id) = if request.background {
+            // Jobs spool their output to disk and keep only a tail, so nothing is buffered
+            let log_path = self.config.jobs.log_path(self.jobs.next_id());
+            match JobLog::create(log_path.clone(), &self.config.jobs) {
+                Ok(log) => job_log = Some(log),
+                Err(e) => tracing::warn!(job_id = self.jobs.next_id(), error = %e, "no log for job"),
+            }
+
+            let job_id = self.jobs.start(NewJob {
+                session,
+                owner: profile.user.clone(),
+                detached: request.detached,
+                request_id,
+                command,
+                pid: process.id(),
+                log_path: job_log.as_ref().map(|_| log_path),
+            });
+            let job = self.jobs.get(job_id).map(|j| j.to_json());
+            let response = serde_json::json!({
+                "type": "job_started",
+                "payload": job
+            });
+            self.send_message(&response.to_string(), id_to);
+            (0, OnOutputLimit::Discard, Some(job_id))
+        } else {
+            let max_bytes = request
+                .max_output_bytes
+                .map_or(self.config.output.max_bytes, |n| n.min(self.config.output.max_bytes));
+            (max_bytes, self.config.output.on_limit, None)
+        };
+
+        let pending = PendingCommand {
+            result,
+            encoding: request.output_encoding,
+            timeout,
+            max_bytes,
+            state_file,
+            events_before,
+            job_id,
+            shell_key: key,
+            running_id,
+        };
+
+        if job_id.is_none() {
+            if let Some(room) = self.shared_rooms.get_mut(&key) {
+                room.record_start(request_id, command, session, pending.result.user.clone());
+                // Let the other members see what is about to run
+                let response = serde_json::json!({
+                    "type": "command_started",
+                    "payload": {
+                        "requestId": request_id,
+                        "command": command,
+                        "runBy": { "sessionId": session, "user": pending.result.user }
+                    }
+                });
+                for member in self.audience(&key) {
+                    self.send_message(&response.to_string(), &member);
+                }
+            }
+        }
+
+        tracing::debug!(command, pid = process.id(), job_id, "command started");
+        let span = Span::current();
+        std::thread::spawn(move || {
+            let _span = span.enter();
+            let job_lobby = lobby.clone();
+            let captured = exec::capture(process, timeout, max_bytes, on_limit, |stream, data| {
+                let Some(job_id) = job_id else { return };
+                // Written here rather than in the lobby, so the log keeps up even with nobody watching
+                if let Some(log) = &mut job_log {
+                    if let Err(e) = log.write(data) {
+                        tracing::warn!(job_id, error = %e, "job log stopped");
+                        job_log = None;
+                    }
+                }
+                job_lobby.do_send(JobOutput { job_id, stream, data: data.to_vec() });
+            });
+            lobby.do_send(CommandFinished { session, pending, captured });
+        });
+    }
+
+    /// Record a background job's end and tell the session watching it, if any
+    fn finish_job(&mut self, job_id: u32, result: &CommandResult, status: JobStatus) {
+        let exit_code = (status == JobStatus::Exited).then_some(result.exit_code);
+        let Some(job) = self.jobs.finish(job_id, status, exit_code) else {
+            return;
+        };
+        let session = job.session;
+        if !self.sessions.contains_key(&session) {
+            tracing::info!(job_id, status = job.status.as_str(), "detached job finished");
+            return;
+        }
+
+        let mut payload = job.to_json();
+        payload["limitExceeded"] = serde_json::json!(result.limit_exceeded);
+        payload["stdoutBytes"] = serde_json::json!(result.stdout_bytes);
+        payload["stderrBytes"] = serde_json::json!(result.stderr_bytes);
+        payload["notes"] = serde_json::json!(result.notes);
+        payload["exit"] = result.exit.to_json();
+        let response = serde_json::json!({
+            "type": "job_finished",
+            "payload": payload
+        });
+        self.send_message(&response.to_string(), &session);
+    }
+
+    fn send_job_output(
+        &self,
+        session: &Uuid,
+        job: &Job,
+        chunk: (u32, Stream, &[u8]),
+        encoding: OutputEncoding,
+    ) {
+        let (sequence, stream, data) = chunk;
+        if encoding == OutputEncoding::Binary {
+            self.send_binary(exec::output_frame(job.request_id, stream, sequence, data), session);
+            return;
+        }
+
+        let data = match encoding {
+            OutputEncoding::Base64 => BASE64.encode(data),
+            _ => String::from_utf8_lossy(data).to_string(),
+        };
+        let response = serde_json::json!({
+            "type": "job_output",
+            "payload": {
+                "jobId": job.id,
+                "requestId": job.request_id,
+                "stream": stream.as_str(),
+                "sequence": sequence,
+                "encoding": encoding,
+                "data": data
+            }
+        });
+        self.send_message(&response.to_string(), session);
+    }
+
+    fn handle_job_request(&mut self, msg_type: &str, request: JobRequest, session: &Uuid) {
+        let user = self.profiles.get(session).and_then(|p| p.user.clone());
+        let Some(job) = self.jobs.visible(request.job_id, session, user.as_deref()) else {
+            self.send_error(session, msg_type, &format!("no job {} for this session", request.job_id));
+            return;
+        };
+
+        match msg_type {
+            "job_attach" => {
+                // A detached job follows whichever of its owner's sessions attached last
+                job.session = *session;
+                job.attached = true;
+                job.attach_encoding = request.output_encoding;
+                let job = self.jobs.get(request.job_id).expect("job looked up above");
+                // Catch the client up with the tail, then stream live output
+                for (sequence, stream, data) in job.tail() {
+                    self.send_job_output(session, job, (*sequence, *stream, data), request.output_encoding);
+                }
+                self.send_jobs_event(session, "job_attached", request.job_id);
+            }
+            "job_detach" => {
+                job.attached = false;
+                self.send_jobs_event(session, "job_detached", request.job_id);
+            }
+            "job_kill" => {
+                if job.status != JobStatus::Running {
+                    self.send_error(session, msg_type, &format!("job {} is not running", request.job_id));
+                    return;
+                }
+
+                #[cfg(unix)]
+                {
+                    let signal_name = request.signal.as_deref().unwrap_or("TERM");
+                    let Some(signal) = exec::parse_signal(signal_name) else {
+                        self.send_error(session, msg_type, &format!("unknown signal '{}'", signal_name));
+                        return;
+                    };
+                    job.kill_requested = true;
+                    exec::signal_pid_group(job.pid, signal);
+                }
+                #[cfg(not(unix))]
+                {
+                    job.kill_requested = true;
+                    exec::kill_pid_group(job.pid);
+                }
+                self.send_jobs_event(session, "job_kill_sent", request.job_id);
+            }
+            "job_log" => {
+                let job = self.jobs.get(request.job_id).expect("job looked up above");
+                self.send_job_log(session, job, request.output_encoding);
+            }
+            _ => {}
+        }
+    }
+
+    /// Stream a job's full log from disk, off the actor thread
+    fn send_job_log(&self, session: &Uuid, job: &Job, encoding: OutputEncoding) {
+        let files = self.jobs.log_files(job);
+        let (Some(socket), Some(binary_socket)) = (self.sessions.get(session), self.binary_sockets.get(session)) else {
+            return;
+        };
+        let (socket, binary_socket) = (socket.clone(), binary_socket.clone());
+        let (job_id, request_id) = (job.id, job.request_id);
+
+        std::thread::spawn(move || {
+            let mut offset = 0u64;
+            let mut sequence = 0u32;
+            for file in files {
+                // A file may rotate away while we read; what's left is still in order
+                let Ok(bytes) = std::fs::read(&file) else { continue };
+                for chunk in bytes.chunks(exec::FRAME_DATA_MAX) {
+                    if encoding == OutputEncoding::Binary {
+                        let data = exec::output_frame(request_id, Stream::Stdout, sequence, chunk);
+                        binary_socket.do_send(WsBinaryMessage { data });
+                    } else {
+                        let data = match encoding {
+                            OutputEncoding::Base64 => BASE64.encode(chunk),
+                            _ => String::from_utf8_lossy(chunk).to_string(),
+                        };
+                        let response = serde_json::json!({
+                            "type": "job_log",
+                            "payload": { "jobId": job_id, "offset": offset, "encoding": encoding, "data": data, "eof": false }
+                        });
+                        socket.do_send(WsMessage { message: response.to_string() });
+                    }
+                    offset += chunk.len() as u64;
+                    sequence += 1;
+                }
+            }
+
+            let response = serde_json::json!({
+                "type": "job_log",
+                "payload": { "jobId": job_id, "offset": offset, "encoding": encoding, "data": "", "eof": true }
+            });
+            socket.do_send(WsMessage { message: response.to_string() });
+        });
+    }
+
+    fn send_jobs_event(&self, session: &Uuid, event: &str, job_id: u32) {
+        let job = self.jobs.get(job_id).map(|j| j.to_json());
+        let response = serde_json::json!({
+            "type": event,
+            "payload": job
+        });
+        self.send_message(&response.to_string(), session);
+    }
+
+    fn send_error(&self, session: &Uuid, request_type: &str, message: &str) {
+        let response = serde_json::json!({
+            "type": "error",
+            "payload": {
+                "requestType": request_type,
+                "message": message,
+                "timestamp": chrono::Utc::now().to_rfc3339()
+            }
+        });
+        self.send_message(&response.to_string(), session);
+    }
+
+    fn member_json(&self, session: &Uuid) -> serde_json::Value {
+        serde_json::json!({
+            "sessionId": session,
+            "user": self.profiles.get(session).and_then(|p| p.user.clone())
+        })
+    }
+
+    /// Span for work done on a session's behalf, matching the one its `WsConn` logs under
+    fn session_span(&self, session: &Uuid) -> Span {
+        let user = self.profiles.get(session).and_then(|p| p.user.as_deref());
+        let room = self.session_rooms.get(session);
+        tracing::info_span!("session", session_id = %session, user, room = room.map(tracing::field::display))
+    }
+
+    /// Name shown for a session; anonymous ones go by a short form of their id
+    fn display_name(&self, session: &Uuid) -> String {
+        self.profiles
+            .get(session)
+            .and_then(|p| p.user.clone())
+            .unwrap_or_else(|| format!("guest-{}", &session.simple().to_string()[..8]))
+    }
+
+    /// Presence details for the roster
+    fn member_info(&self, session: &Uuid) -> serde_json::Value {
+        let profile = self.profiles.get(session);
+        let user = profile.and_then(|p| p.user.clone());
+        let now = Utc::now();
+        let room = self.session_rooms.get(session).and_then(|r| self.shared_rooms.get(r));
+
+        serde_json::json!({
+            "sessionId": session,
+            "user": user,
+            "displayName": self.display_name(session),
+            "role": profile.map(|p| p.role.clone()),
+            "shell": profile.map(|p| p.shell.name.clone()),
+            "connectedAt": self.connected_at.get(session).map(|t| t.to_rfc3339()),
+            "idleSecs": self.last_active.get(session).map(|t| (now - *t).num_seconds().max(0)),
+            "readOnly": profile.is_some_and(|p| p.read_only),
+            "driver": room.is_some_and(|r| r.driver == Some(*session)),
+            "owner": room.is_some_and(|r| r.owner == *session)
+        })
+    }
+
+    /// Everything the admin API shows about a session
+    fn session_json(&self, session: &Uuid) -> serde_json::Value {
+        let mut json = self.member_info(session);
+        let profile = self.profiles.get(session);
+        let room = self.session_rooms.get(session);
+        json["roomId"] = serde_json::json!(room);
+        json["roomSlug"] = serde_json::json!(room.and_then(|r| self.directory.get(r)).map(|r| &r.slug));
+        json["remoteAddr"] = serde_json::json!(profile.and_then(|p| p.remote_addr.as_deref()));
+        json["forwardedFor"] = serde_json::json!(profile.and_then(|p| p.forwarded_for.as_deref()));
+        json["cwd"] = serde_json::json!(self.curr_dir.get(&self.shell_key(session)));
+        json["lastActive"] = serde_json::json!(self.last_active.get(session).map(|t| t.to_rfc3339()));
+        json["invite"] = serde_json::json!(profile.and_then(|p| p.invite));
+        json["expiresAt"] = serde_json::json!(profile.and_then(|p| p.expires_at).map(|t| t.to_rfc3339()));
+        json["running"] = self
+            .running
+            .get(session)
+            .into_iter()
+            .flatten()
+            .map(|c| c.to_json())
+            .collect();
+        json
+    }
+
+    fn send_roster(&self, room_id: &Uuid, id_to: &Uuid) {
+        let mut members: Vec<_> = self.rooms.get(room_id).map(|m| m.iter().collect()).unwrap_or_default();
+        members.sort_by_key(|s| self.connected_at.get(s));
+        let response = serde_json::json!({
+            "type": "roster",
+            "payload": {
+                "roomId": room_id,
+                "members": members.into_iter().map(|s| self.member_info(s)).collect::<Vec<_>>()
+            }
+        });
+        self.send_message(&response.to_string(), id_to);
+    }
+
+    /// Chat to the whole room, or privately to one member of it
+    fn handle_chat(&mut self, msg_type: &str, payload: &serde_json::Value, session: &Uuid, room_id: &Uuid) {
+        let text = payload["text"].as_str().unwrap_or_default();
+        if let Err(e) = chat::validate_text(text) {
+            self.send_error(session, msg_type, &e);
+            return;
+        }
+
+        let message = ChatMessage {
+            id: self.next_chat_id + 1,
+            session: *session,
+            user: self.profiles.get(session).and_then(|p| p.user.clone()),
+            display_name: self.display_name(session),
+            text: text.to_string(),
+            sent_at: Utc::now(),
+        };
+
+        if msg_type == "direct_message" {
+            let to = payload["to"].as_str().and_then(|s| Uuid::parse_str(s).ok());
+            let Some(to) = to.filter(|to| self.rooms.get(room_id).is_some_and(|m| m.contains(to))) else {
+                self.send_error(session, msg_type, "`to` must be the sessionId of a member of this room");
+                return;
             };
-            
-            match new_path.canonicalize() {
-                Ok(resolved) => {
-                    self.curr_dir.insert(id_to.clone(), resolved.clone());
-                    let current_path = resolved.to_string_lossy();
+            self.next_chat_id += 1;
+            let mut body = message.to_json();
+            body["to"] = serde_json::json!({ "sessionId": to, "displayName": self.display_name(&to) });
+            let response = serde_json::json!({ "type": "direct_message", "payload": body });
+            // The sender gets a copy so every client shows the conversation the same way
+            self.send_message(&response.to_string(), &to);
+            if to != *session {
+                self.send_message(&response.to_string(), session);
+            }
+            return;
+        }
+
+        self.next_chat_id += 1;
+        let mut body = self.chats.entry(*room_id).or_default().push(message).to_json();
+        body["roomId"] = serde_json::json!(room_id);
+        let response = serde_json::json!({ "type": "chat_message", "payload": body });
+        for member in self.rooms.get(room_id).into_iter().flatten() {
+            self.send_message(&response.to_string(), member);
+        }
+    }
+
+    /// Disconnect one session, telling it why with a `session_closed` first
+    fn close_session(&self, session: &Uuid, reason: &str) {
+        let notice = serde_json::json!({
+            "type": "session_closed",
+            "payload": { "reason": reason }
+        });
+        self.send_message(&notice.to_string(), session);
+        if let Some(socket) = self.close_sockets.get(session) {
+            socket.do_send(CloseSession { code: CloseCode::Normal, reason: reason.to_string() });
+        }
+    }
+
+    /// When a session must go, and why: it went idle, got too old, or its invite ran out
+    fn session_deadline(&self, session: &Uuid) -> Option<(DateTime<Utc>, &'static str)> {
+        let profile = self.profiles.get(session)?;
+        let policy = self.config.role(&profile.role);
+        let after = |t: &DateTime<Utc>, secs: u64| *t + chrono::Duration::seconds(secs as i64);
+
+        let mut deadlines = Vec::new();
+        if policy.idle_timeout_secs > 0 {
+            deadlines.extend(self.last_active.get(session).map(|t| (after(t, policy.idle_timeout_secs), "idle")));
+        }
+        if policy.max_session_secs > 0 {
+            deadlines.extend(self.connected_at.get(session).map(|t| (after(t, policy.max_session_secs), "lifetime")));
+        }
+        deadlines.extend(profile.expires_at.map(|t| (t, "invite")));
+        deadlines.into_iter().min_by_key(|(t, _)| *t)
+    }
+
+    /// Warn sessions coming up on their deadline with `session_expiring`, and
+    /// close the ones past it with `session_expired`
+    fn expire_sessions(&mut self) {
+        let now = Utc::now();
+        let warning = chrono::Duration::seconds(self.config.session_warning_secs as i64);
+        let sessions: Vec<Uuid> = self.sessions.keys().copied().collect();
+
+        for session in sessions {
+            let Some((deadline, reason)) = self.session_deadline(&session) else { continue };
+            if deadline <= now {
+                tracing::info!(session_id = %session, reason, "session expired");
+                let notice = serde_json::json!({
+                    "type": "session_expired",
+                    "payload": { "reason": reason }
+                });
+                self.send_message(&notice.to_string(), &session);
+                if let Some(socket) = self.close_sockets.get(&session) {
+                    socket.do_send(CloseSession { code: CloseCode::Normal, reason: "session_expired".to_string() });
+                }
+            } else if deadline - warning <= now && self.expiry_warned.get(&session) != Some(&deadline) {
+                // Idle deadlines move with activity, so a later one gets its own warning
+                let notice = serde_json::json!({
+                    "type": "session_expiring",
+                    "payload": {
+                        "reason": reason,
+                        "expiresAt": deadline.to_rfc3339(),
+                        "secondsLeft": (deadline - now).num_seconds()
+                    }
+                });
+                self.send_message(&notice.to_string(), &session);
+                self.expiry_warned.insert(session, deadline);
+            }
+        }
+    }
+
+    /// Every process the server started that is still running
+    fn live_pids(&self) -> Vec<u32> {
+        let mut pids: HashSet<u32> = self.running.values().flatten().map(|c| c.pid).collect();
+        pids.extend(self.orphaned.values().map(|c| c.pid));
+        pids.extend(self.execs.values().map(|(_, c)| c.pid));
+        // Detached jobs have left their session's `running` list behind
+        pids.extend(self.jobs.running_pids());
+        pids.extend(self.schedules.running_pids());
+        pids.into_iter().collect()
+    }
+
+    /// One step of a shutdown: wait for processes until the deadline, then TERM,
+    /// then KILL, and once they are gone close every socket
+    fn drain(&mut self, ctx: &mut Context<Self>) {
+        let pids = self.live_pids();
+        let Some(draining) = self.draining.as_mut() else { return };
+        let now = Instant::now();
+
+        // Give up on anything that survives SIGKILL, e.g. stuck in the kernel
+        let gave_up = draining.killed_at.is_some_and(|t| now - t >= Duration::from_secs(1));
+        if pids.is_empty() || gave_up {
+            ctx.cancel_future(draining.tick);
+            if let Some(done) = draining.done.take() {
+                for (session, socket) in &self.close_sockets {
+                    tracing::debug!(session_id = %session, "closing session for shutdown");
+                    socket.do_send(CloseSession { code: CloseCode::Away, reason: "server shutting down".to_string() });
+                }
+                let _ = done.send(());
+            }
+            return;
+        }
+
+        match (draining.termed_at, draining.killed_at) {
+            (None, _) if now >= draining.deadline => {
+                tracing::info!(processes = pids.len(), "sending SIGTERM");
+                #[cfg(unix)]
+                for pid in &pids {
+                    exec::signal_pid_group(*pid, libc::SIGTERM);
+                }
+                draining.termed_at = Some(now);
+            }
+            (Some(termed), None) if now - termed >= shutdown::KILL_AFTER => {
+                tracing::warn!(processes = pids.len(), "sending SIGKILL");
+                for pid in &pids {
+                    exec::kill_pid_group(*pid);
+                }
+                draining.killed_at = Some(now);
+            }
+            _ => {}
+        }
+    }
+
+    /// Remove a room and disconnect its members, who each get a `room_closed` first
+    fn close_room(&mut self, room_id: &Uuid, reason: &str) {
+        self.directory.remove(room_id);
+        let notice = serde_json::json!({
+            "type": "room_closed",
+            "payload": { "roomId": room_id, "reason": reason }
+        });
+        for member in self.rooms.get(room_id).into_iter().flatten() {
+            self.send_message(&notice.to_string(), member);
+            if let Some(socket) = self.close_sockets.get(member) {
+                socket.do_send(CloseSession { code: CloseCode::Normal, reason: format!("room {}", reason) });
+            }
+        }
+    }
+
+    /// Tell a shared room who drives and who owns it now
+    fn broadcast_control(&self, room_id: &Uuid, reason: &str) {
+        let Some(room) = self.shared_rooms.get(room_id) else { return };
+        let response = serde_json::json!({
+            "type": "control_changed",
+            "payload": {
+                "driver": room.driver.map(|s| self.member_json(&s)),
+                "owner": self.member_json(&room.owner),
+                "reason": reason
+            }
+        });
+        for member in self.audience(room_id) {
+            self.send_message(&response.to_string(), &member);
+        }
+    }
+
+    /// Input-lock handoff between a shared room's driver and its watchers
+    fn handle_control(&mut self, msg_type: &str, payload: &serde_json::Value, session: &Uuid) {
+        let room_id = self.shell_key(session);
+        let Some(room) = self.shared_rooms.get_mut(&room_id) else {
+            return self.send_error(session, msg_type, "not in a shared room");
+        };
+        // `control_grant` and `control_deny` name the watcher they answer
+        let target = payload["sessionId"].as_str().and_then(|s| Uuid::parse_str(s).ok());
+
+        match msg_type {
+            "control_request" => {
+                if room.can_type(session) {
+                    return self.send_error(session, msg_type, "you already drive this room");
+                }
+                let Some(driver) = room.driver else {
+                    // Nobody holds the lock, so it is free to take
+                    room.set_driver(Some(*session));
+                    return self.broadcast_control(&room_id, "granted");
+                };
+                if !room.control_requests.contains(session) {
+                    room.control_requests.push(*session);
+                }
+                let response = serde_json::json!({
+                    "type": "control_requested",
+                    "payload": { "from": self.member_json(session) }
+                });
+                self.send_message(&response.to_string(), &driver);
+            }
+            "control_grant" | "control_deny" => {
+                if !room.can_type(session) {
+                    return self.send_error(session, msg_type, "only the driver can answer control requests");
+                }
+                let Some(target) = target.filter(|t| room.control_requests.contains(t)) else {
+                    return self.send_error(session, msg_type, "no pending control request from that session");
+                };
+
+                if msg_type == "control_grant" {
+                    if self.profiles.get(&target).is_some_and(|p| p.read_only) {
+                        return self.send_error(session, msg_type, "that session is read-only");
+                    }
+                    room.set_driver(Some(target));
+                    self.broadcast_control(&room_id, "granted");
+                } else {
+                    room.control_requests.retain(|s| *s != target);
                     let response = serde_json::json!({
-                        "type": "command_output",
-                        "payload": {
-                            "command": command,
-                            "stdout": "",
-                            "stderr": "",
-                            "exitCode": 0,
-                            "currentDirectory": current_path
-                        }
+                        "type": "control_denied",
+                        "payload": { "by": self.member_json(session) }
                     });
-                    self.send_message(&response.to_string(), id_to);
+                    self.send_message(&response.to_string(), &target);
                 }
+            }
+            "control_release" => {
+                if !room.can_type(session) {
+                    return self.send_error(session, msg_type, "you don't drive this room");
+                }
+                room.set_driver(None);
+                self.broadcast_control(&room_id, "released");
+            }
+            "control_takeover" => {
+                if room.owner != *session {
+                    return self.send_error(session, msg_type, "only the room owner can take over");
+                }
+                room.set_driver(Some(*session));
+                self.broadcast_control(&room_id, "takeover");
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_schedule_request(&mut self, msg_type: &str, payload: &serde_json::Value, session: &Uuid) {
+        let Some(user) = self.profiles.get(session).and_then(|p| p.user.clone()) else {
+            self.send_error(session, msg_type, "scheduling needs a signed-in user");
+            return;
+        };
+
+        let response = match msg_type {
+            "schedule_add" => {
+                let request = match serde_json::from_value::<ScheduleAdd>(payload.clone()) {
+                    Ok(request) => request,
+                    Err(e) => return self.send_error(session, msg_type, &format!("invalid payload: {}", e)),
+                };
+                let added = self.schedules.add(&user, request.name, &request.command, request.cron.as_deref(), request.at);
+                match added {
+                    Ok(id) => {
+                        tracing::info!(user = %user, schedule_id = id, "schedule added");
+                        serde_json::json!({ "type": "schedule_added", "payload": self.schedules.get(id).map(|s| s.to_json()) })
+                    }
+                    Err(e) => return self.send_error(session, msg_type, &e),
+                }
+            }
+            "schedules" => {
+                let schedules: Vec<_> = self.schedules.list(&user).iter().map(|s| s.to_json()).collect();
+                serde_json::json!({ "type": "schedule_list", "payload": { "schedules": schedules } })
+            }
+            _ => {
+                let id = match serde_json::from_value::<ScheduleRef>(payload.clone()) {
+                    Ok(request) => request.schedule_id,
+                    Err(e) => return self.send_error(session, msg_type, &format!("invalid payload: {}", e)),
+                };
+                let Some(schedule) = self.schedules.owned(id, &user) else {
+                    return self.send_error(session, msg_type, &format!("no schedule {}", id));
+                };
+
+                if msg_type == "schedule_history" {
+                    let runs: Vec<_> = schedule.history().map(|r| r.to_json()).collect();
+                    serde_json::json!({ "type": "schedule_history", "payload": { "scheduleId": id, "runs": runs } })
+                } else {
+                    // A run already in progress is left to finish; it just won't be recorded
+                    self.schedules.remove(id, &user);
+                    serde_json::json!({ "type": "schedule_removed", "payload": { "scheduleId": id } })
+                }
+            }
+        };
+        self.send_message(&response.to_string(), session);
+    }
+
+    /// Start every scheduled command that is due, as its owner
+    fn run_due_schedules(&mut self, lobby: Addr<Lobby>) {
+        if self.draining.is_some() {
+            return;
+        }
+        for id in self.schedules.take_due(Utc::now()) {
+            let Some(schedule) = self.schedules.get(id) else { continue };
+            let started_at = Utc::now();
+
+            let Some(user) = self.config.find_user(&schedule.owner) else {
+                let mut run = ScheduleRun::new(started_at, RunStatus::SpawnFailed, ExitReport::spawn_failed(), &[], &[]);
+                run.notes.push(format!("user '{}' no longer exists", schedule.owner));
+                self.finish_schedule_run(id, run);
+                continue;
+            };
+            let shell_name = user.shell.clone().unwrap_or_else(|| self.config.default_shell.clone());
+            let Some(shell) = self.config.shell(&shell_name) else {
+                let mut run = ScheduleRun::new(started_at, RunStatus::SpawnFailed, ExitReport::spawn_failed(), &[], &[]);
+                run.notes.push(format!("shell '{}' is not allowed", shell_name));
+                self.finish_schedule_run(id, run);
+                continue;
+            };
+            let profile = self.config.session_profile(Some(user), shell);
+
+            let mut process_cmd = profile.shell.command(&schedule.command);
+            process_cmd.envs(&profile.env);
+            if let Some(cwd) = &profile.cwd {
+                process_cmd.current_dir(cwd);
+            }
+            profile.limits.apply(&mut process_cmd);
+            exec::prepare(&mut process_cmd);
+
+            let process = match process_cmd.spawn() {
+                Ok(process) => process,
                 Err(e) => {
-                    let response = serde_json::json!({
-                        "type": "command_output",
-                        "payload": {
-                            "command": command,
-                            "stdout": "",
-                            "stderr": format!("cd: \"{}\": {}", target_path, e),
-                            "exitCode": 1,
-                            "currentDirectory": curr_dir.to_string_lossy()
-                        }
-                    });
-                    self.send_message(&response.to_string(), id_to);
+                    let mut run = ScheduleRun::new(started_at, RunStatus::SpawnFailed, ExitReport::spawn_failed(), &[], &[]);
+                    run.notes.push(format!("Failed to execute command: {}", e));
+                    self.finish_schedule_run(id, run);
+                    continue;
+                }
+            };
+            if let Some(schedule) = self.schedules.get_mut(id) {
+                schedule.running_pid = Some(process.id());
+            }
+
+            let timeout = Duration::from_secs(self.config.role(&profile.role).max_timeout_secs);
+            let (max_bytes, on_limit) = (self.config.output.max_bytes, self.config.output.on_limit);
+            let lobby = lobby.clone();
+            std::thread::spawn(move || {
+                let captured = exec::capture(process, Some(timeout), max_bytes, on_limit, |_, _| {});
+                lobby.do_send(ScheduleFinished { schedule_id: id, started_at, timeout, captured });
+            });
+        }
+    }
+
+    /// Run one command in the shells of several sessions or rooms at once. Each
+    /// shell runs it as if its own session had typed it, except that `cd` doesn't stick.
+    fn handle_fanout(&mut self, payload: &serde_json::Value, initiator: &Uuid, lobby: Addr<Lobby>) {
+        let request = match serde_json::from_value::<FanoutRequest>(payload.clone()) {
+            Ok(request) => request,
+            Err(e) => return self.send_error(initiator, "fanout", &format!("invalid payload: {}", e)),
+        };
+        if request.command.trim().is_empty() {
+            return self.send_error(initiator, "fanout", "command must not be empty");
+        }
+        let Some(profile) = self.profiles.get(initiator) else { return };
+        let policy = self.config.role(&profile.role);
+        let me = profile.user.clone();
+        let secs = request.timeout_secs.unwrap_or(self.config.command_timeout_secs);
+        if secs == 0 {
+            return self.send_error(initiator, "fanout", "timeoutSecs must be at least 1");
+        }
+        let timeout = Duration::from_secs(secs.min(policy.max_timeout_secs));
+
+        let mut skipped = Vec::new();
+        let mut wanted = request.sessions.clone();
+        for room in &request.rooms {
+            match self.directory.resolve(room) {
+                Some(room) => {
+                    let mut members: Vec<Uuid> = self.rooms.get(&room.id).into_iter().flatten().copied().collect();
+                    members.sort_by_key(|s| self.connected_at.get(s));
+                    wanted.extend(members);
                 }
+                None => skipped.push(serde_json::json!({ "target": room, "reason": "no such room" })),
+            }
+        }
+
+        // Non-admins may only reach their own sessions; a shared room's shell is one target
+        let mut shells = HashSet::new();
+        let mut targets = Vec::new();
+        for session in wanted {
+            let Some(target) = self.profiles.get(&session) else {
+                skipped.push(serde_json::json!({ "target": session, "reason": "no such session" }));
+                continue;
+            };
+            if !(policy.admin || session == *initiator || (me.is_some() && target.user == me)) {
+                skipped.push(serde_json::json!({ "target": session, "reason": "not permitted" }));
+                continue;
+            }
+            let key = self.shell_key(&session);
+            // A shared room's shell takes input only from its driver, fan-out included
+            let locked = self.shared_rooms.get(&key).is_some_and(|room| !room.can_type(initiator) && !room.can_type(&session));
+            if locked && !policy.admin {
+                skipped.push(serde_json::json!({ "target": session, "reason": "not the room's driver" }));
+                continue;
+            }
+            if shells.insert(key) {
+                let room = self.session_rooms.get(&session).copied().unwrap_or(key);
+                targets.push(FanoutTarget::new(session, room, target.user.clone(), self.shared_rooms.contains_key(&key)));
+            }
+        }
+        if targets.is_empty() {
+            return self.send_error(initiator, "fanout", "no targets to run on");
+        }
+        if targets.len() > fanout::MAX_TARGETS {
+            return self.send_error(initiator, "fanout", &format!("at most {} targets", fanout::MAX_TARGETS));
+        }
+
+        let request_id = request.request_id.unwrap_or_else(|| self.next_id());
+        let sessions: Vec<Uuid> = targets.iter().map(|t| t.session).collect();
+        let fanout_id = self.fanouts.start(request_id, *initiator, &request.command, targets);
+        if let Some(fanout) = self.fanouts.get_mut(fanout_id) {
+            let response = serde_json::json!({
+                "type": "fanout_started",
+                "payload": {
+                    "fanoutId": fanout_id,
+                    "requestId": request_id,
+                    "command": request.command,
+                    "timeoutSecs": timeout.as_secs(),
+                    "targets": fanout.targets.iter().map(|t| fanout.target_json(t)).collect::<Vec<_>>(),
+                    "skipped": skipped
+                }
+            });
+            self.send_message(&response.to_string(), initiator);
+        }
+
+        let notice = serde_json::json!({
+            "type": "fanout_run",
+            "payload": { "fanoutId": fanout_id, "command": request.command, "runBy": self.member_json(initiator) }
+        });
+        let (max_bytes, on_limit) = (self.config.output.max_bytes, self.config.output.on_limit);
+        for (index, session) in sessions.into_iter().enumerate() {
+            let process = match self.spawn_in_session(&session, &request.command) {
+                Ok(process) => process,
+                Err(e) => {
+                    let result = TargetResult {
+                        exit: ExitReport::spawn_failed(),
+                        stdout: Vec::new(),
+                        stderr: Vec::new(),
+                        truncated: false,
+                        notes: vec![format!("Failed to execute command: {}", e)],
+                    };
+                    self.finish_fanout_target(fanout_id, index, result);
+                    continue;
+                }
+            };
+
+            let pid = process.id();
+            if let Some(target) = self.fanouts.get_mut(fanout_id).and_then(|f| f.targets.get_mut(index)) {
+                target.pid = Some(pid);
+            }
+            let id = self.next_id();
+            self.running.entry(session).or_default().push(RunningCommand {
+                id,
+                request_id,
+                pid,
+                detached: false,
+                command: request.command.clone(),
+                started_at: Utc::now(),
+            });
+            // Whoever watches that shell sees that something was run in it
+            for member in self.audience(&session) {
+                if member != *initiator {
+                    self.send_message(&notice.to_string(), &member);
+                }
+            }
+
+            let lobby = lobby.clone();
+            std::thread::spawn(move || {
+                let captured = exec::capture(process, Some(timeout), max_bytes, on_limit, |_, _| {});
+                lobby.do_send(FanoutFinished { fanout_id, index, captured });
+            });
+        }
+    }
+
+    /// Start a command in a session's shell, cwd, limits and cgroup
+    fn spawn_in_session(&self, session: &Uuid, command: &str) -> std::io::Result<std::process::Child> {
+        let profile = self
+            .profiles
+            .get(session)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "session is gone"))?;
+        let key = self.shell_key(session);
+        let shell = self.shared_rooms.get(&key).map_or(&profile.shell, |room| &room.shell);
+        let curr_dir = self
+            .curr_dir
+            .get(&key)
+            .cloned()
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")));
+
+        let mut process_cmd = shell.command(command);
+        if let Some(cgroup) = self.cgroups.get(session) {
+            cgroup.attach(&mut process_cmd);
+        }
+        process_cmd.envs(&profile.env);
+        profile.limits.apply(&mut process_cmd);
+        exec::prepare(&mut process_cmd);
+        process_cmd.current_dir(curr_dir).spawn()
+    }
+
+    /// Report one fan-out target to its initiator, and the whole fan-out once all are in
+    fn finish_fanout_target(&mut self, fanout_id: u32, index: usize, result: TargetResult) {
+        METRICS.record_exit("fanout", &result.exit);
+        let Some(fanout) = self.fanouts.record(fanout_id, index, result) else { return };
+        let target = &fanout.targets[index];
+        if let Some(commands) = self.running.get_mut(&target.session) {
+            commands.retain(|c| Some(c.pid) != target.pid);
+        }
+
+        let initiator = fanout.initiator;
+        let response = serde_json::json!({
+            "type": "fanout_progress",
+            "payload": { "fanoutId": fanout_id, "target": fanout.target_json(target) }
+        });
+        self.send_message(&response.to_string(), &initiator);
+
+        if let Some(fanout) = self.fanouts.take_done(fanout_id) {
+            let response = serde_json::json!({
+                "type": "fanout_result",
+                "payload": fanout.to_json()
+            });
+            self.send_message(&response.to_string(), &initiator);
+        }
+    }
+
+    /// Store a run in its schedule's history and tell every session of the owner
+    fn finish_schedule_run(&mut self, id: u32, run: ScheduleRun) {
+        METRICS.record_exit("schedule", &run.exit);
+        let run_json = run.to_json();
+        let Some(schedule) = self.schedules.record(id, run) else {
+            return;
+        };
+
+        let response = serde_json::json!({
+            "type": "schedule_run",
+            "payload": {
+                "scheduleId": id,
+                "name": schedule.name,
+                "command": schedule.command,
+                "nextRun": schedule.next_run.map(|t| t.to_rfc3339()),
+                "run": run_json
+            }
+        });
+        let owner = schedule.owner.clone();
+        for (session, profile) in &self.profiles {
+            if profile.user.as_deref() == Some(owner.as_str()) {
+                self.send_message(&response.to_string(), session);
             }
-            return;
         }
+    }
 
-        // Always include current directory in response
-        let current_dir_str = curr_dir.to_string_lossy().to_string();
+    /// Turn a finished command into a `command_output` for its session
+    fn finish_command(&mut self, session: &Uuid, pending: PendingCommand, captured: Captured) {
+        let PendingCommand {
+            mut result,
+            encoding,
+            timeout,
+            max_bytes,
+            state_file,
+            events_before,
+            job_id,
+            shell_key,
+            running_id,
+        } = pending;
 
-        // Spawn process (non-blocking)
-        let child = if cfg!(target_os = "windows") {
-            Command::new("cmd")
-                .current_dir(&curr_dir)
-                .args(["/C", command])
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-        } else {
-            Command::new("bash")
-                .current_dir(&curr_dir)
-                .arg("-c")
-                .arg(command)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()
-        };
+        if let Some(commands) = self.running.get_mut(session) {
+            commands.retain(|c| c.id != running_id);
+        }
+        self.orphaned.remove(&running_id);
 
-        match child {
-            Ok(mut process) => {
-                let timeout = Duration::from_secs(15);
-                match process.wait_timeout(timeout) {
-                    Ok(Some(status)) => {
-                        match process.wait_with_output() {
-                            Ok(output) => {
-                                let stdout = String::from_utf8_lossy(&output.stdout);
-                                let stderr = String::from_utf8_lossy(&output.stderr);
-                                let exit_code = status.code().unwrap_or(-1);
-
-                                let response = serde_json::json!({
-                                    "type": "command_output",
-                                    "payload": {
-                                        "command": command,
-                                        "stdout": stdout.trim(),
-                                        "stderr": stderr.trim(),
-                                        "exitCode": exit_code,
-                                        "currentDirectory": current_dir_str
-                                    }
-                                });
-                                
-                                self.send_message(&response.to_string(), id_to);
-                            }
-                            Err(e) => {
-                                let response = serde_json::json!({
-                                    "type": "command_output",
-                                    "payload": {
-                                        "command": command,
-                                        "stdout": "",
-                                        "stderr": format!("Failed to read command output: {}", e),
-                                        "exitCode": -1,
-                                        "currentDirectory": current_dir_str
-                                    }
-                                });
-                                self.send_message(&response.to_string(), id_to);
-                            }
-                        }
-                    }
-                    Ok(None) => {
-                        // Timed out → kill process
-                        let _ = process.kill();
-                        let _ = process.wait();
+        result.exit = ExitReport::from_captured(&captured);
+        METRICS.record_exit(if job_id.is_some() { "job" } else { "command" }, &result.exit);
+        tracing::debug!(exit = result.exit.kind.as_str(), code = result.exit.code, duration_ms = result.exit.duration.as_millis() as u64, "command finished");
+        result.stdout = captured.stdout;
+        result.stderr = captured.stderr;
+        result.truncated = captured.truncated;
+        result.stdout_bytes = captured.stdout_bytes;
+        result.stderr_bytes = captured.stderr_bytes;
 
-                        let response = serde_json::json!({
-                            "type": "command_output",
-                            "payload": {
-                                "command": command,
-                                "stdout": "",
-                                "stderr": format!("Command timed out after {} seconds", timeout.as_secs()),
-                                "exitCode": -1,
-                                "currentDirectory": current_dir_str
-                            }
-                        });
-                        self.send_message(&response.to_string(), id_to);
-                    }
-                    Err(e) => {
-                        let response = serde_json::json!({
-                            "type": "command_output",
-                            "payload": {
-                                "command": command,
-                                "stdout": "",
-                                "stderr": format!("Process wait error: {}", e),
-                                "exitCode": -1,
-                                "currentDirectory": current_dir_str
-                            }
-                        });
-                        self.send_message(&response.to_string(), id_to);
-                    }
-                }
+        match &captured.status {
+            Ok(_) if captured.timed_out => {
+                let secs = timeout.map_or(0, |t| t.as_secs());
+                result.add_note(format!("Command timed out after {} seconds", secs));
             }
-            Err(e) => {
-                let error_response = serde_json::json!({
-                    "type": "command_output",
-                    "payload": {
-                        "command": command,
-                        "stdout": "",
-                        "stderr": format!("Failed to execute command: {}", e),
-                        "exitCode": -1,
-                        "currentDirectory": current_dir_str
-                    }
-                });
-                self.send_message(&error_response.to_string(), id_to);
+            Ok(status) => {
+                let events_after = self.cgroups.get(session).map(|c| c.events()).unwrap_or_default();
+                result.limit_exceeded = limits::limit_hit(status, &events_before, &events_after);
+                result.exit_code = status.code().unwrap_or(-1);
+            }
+            Err(e) => result.add_note(format!("Process wait error: {}", e)),
+        }
+
+        if let Some(limit) = result.limit_exceeded {
+            result.add_note(format!("Command stopped: {} limit exceeded", limit));
+        }
+        // Jobs stream everything and keep no buffer, so there is nothing to truncate
+        if captured.truncated && job_id.is_none() {
+            result.add_note(format!(
+                "Output truncated: command wrote {} bytes, limit is {}",
+                captured.stdout_bytes + captured.stderr_bytes,
+                max_bytes
+            ));
+        }
+
+        if let Some(job_id) = job_id {
+            let status = match result.exit.kind {
+                ExitKind::Exited => JobStatus::Exited,
+                ExitKind::Signaled => JobStatus::Killed,
+                ExitKind::TimedOut => JobStatus::TimedOut,
+                ExitKind::SpawnFailed | ExitKind::Unknown => JobStatus::Failed,
+            };
+            self.finish_job(job_id, &result, status);
+            return;
+        }
+
+        // The session, or its shared room, may be gone by now
+        let still_open = self.curr_dir.contains_key(&shell_key);
+        if let Some(state_file) = state_file {
+            if still_open {
+                self.apply_cwd_report(&shell_key, &state_file);
+            } else {
+                let _ = std::fs::remove_file(state_file);
             }
         }
+        if let Some(room) = self.shared_rooms.get_mut(&shell_key) {
+            room.record_finish(result.request_id, result.exit_code);
+        }
+
+        if still_open {
+            self.send_command_result(&shell_key, &result, encoding);
+        } else {
+            tracing::info!(exit_code = result.exit_code, "detached command finished");
+        }
     }
 }
 
 impl Actor for Lobby {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SCHEDULE_TICK, |lobby, ctx| lobby.run_due_schedules(ctx.address()));
+        ctx.run_interval(ROOM_SWEEP, |lobby, _| {
+            for id in lobby.directory.expired(Utc::now()) {
+                tracing::info!(room = %id, "room expired");
+                lobby.close_room(&id, "expired");
+            }
+            lobby.expire_sessions();
+        });
+    }
 }
 
 impl Handler<Connect> for Lobby {
     type Result = ();
 
     fn handle(&mut self, msg: Connect, _: &mut Context<Self>) {
+        // Admission was checked at the handshake, but the room may have closed or filled up since
+        let members = self.rooms.get(&msg.lobby_id).map_or(0, |m| m.len());
+        let refused = match self.directory.get(&msg.lobby_id) {
+            None => Some((CloseCode::Normal, "room closed".to_string())),
+            Some(room) if room.max_members.is_some_and(|max| members >= max) => {
+                Some((CloseCode::Again, format!("room is full ({} members)", members)))
+            }
+            Some(_) if self.sessions.len() >= self.config.max_sessions => {
+                Some((CloseCode::Again, format!("server is full ({} sessions)", self.sessions.len())))
+            }
+            Some(_) => None,
+        };
+        if let Some((code, reason)) = refused {
+            tracing::info!(session_id = %msg.self_id, room = %msg.lobby_id, reason = %reason, "session refused on connect");
+            msg.close_addr.do_send(CloseSession { code, reason });
+            return;
+        }
+
         self.rooms
             .entry(msg.lobby_id)
-            .or_insert_with(HashSet::new)
+            .or_default()
             .insert(msg.self_id);
 
         self.sessions.insert(msg.self_id, msg.addr);
+        self.binary_sockets.insert(msg.self_id, msg.binary_addr);
+        self.close_sockets.insert(msg.self_id, msg.close_addr);
+        if let Some(room) = self.directory.get_mut(&msg.lobby_id) {
+            room.empty_since = None;
+        }
+        self.session_rooms.insert(msg.self_id, msg.lobby_id);
+        self.connected_at.insert(msg.self_id, Utc::now());
+        self.last_active.insert(msg.self_id, Utc::now());
+        let mut shell_name = msg.profile.shell.name.clone();
 
-        // Initialize current directory for this session
-        let initial_dir = std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
-        self.curr_dir.insert(msg.self_id, initial_dir.clone());
+        if let Some(root) = &self.cgroup_root {
+            match SessionCgroup::create(root, &msg.self_id, &msg.profile.limits) {
+                Ok(cgroup) => {
+                    self.cgroups.insert(msg.self_id, cgroup);
+                }
+                Err(e) => tracing::warn!(session_id = %msg.self_id, error = %e, "failed to create cgroup"),
+            }
+        }
+        // Initialize current directory for this session, from the user's profile if set
+        let initial_dir = msg
+            .profile
+            .cwd
+            .clone()
+            .unwrap_or_else(|| std::env::current_dir().unwrap_or_else(|_| PathBuf::from("/")));
+        // The first member of a shared room picks its shell and starting directory
+        let is_new_room = self.rooms.get(&msg.lobby_id).is_some_and(|members| members.len() == 1);
+        if msg.shared && is_new_room {
+            let mut room = SharedRoom::new(msg.profile.shell.clone(), msg.self_id);
+            if msg.profile.read_only {
+                room.set_driver(None);
+            }
+            self.shared_rooms.insert(msg.lobby_id, room);
+            self.curr_dir.insert(msg.lobby_id, initial_dir.clone());
+        }
+        let shared_room = self.shared_rooms.get(&msg.lobby_id);
+        if let Some(room) = shared_room {
+            shell_name = room.shell.name.clone();
+        }
+        let initial_dir = match shared_room {
+            Some(_) => self.curr_dir.get(&msg.lobby_id).cloned().unwrap_or(initial_dir),
+            None => {
+                self.curr_dir.insert(msg.self_id, initial_dir.clone());
+                initial_dir
+            }
+        };
+        self.profiles.insert(msg.self_id, msg.profile);
 
         // Send JSON formatted welcome message with current directory
         let welcome_message = serde_json::json!({
@@ -234,11 +1541,42 @@ impl Handler<Connect> for Lobby {
             "payload": {
                 "message": format!("Connected! Your session ID is {}", msg.self_id),
                 "timestamp": chrono::Utc::now().to_rfc3339(),
-                "currentDirectory": initial_dir.to_string_lossy()
+                "currentDirectory": initial_dir.to_string_lossy(),
+                "shell": shell_name,
+                "shared": self.shared_rooms.contains_key(&msg.lobby_id),
+                "driver": self.shared_rooms.get(&msg.lobby_id).and_then(|r| r.driver).map(|s| self.member_json(&s)),
+                "owner": self.shared_rooms.get(&msg.lobby_id).map(|r| self.member_json(&r.owner))
             }
         });
         
         self.send_message(&welcome_message.to_string(), &msg.self_id);
+
+        // Late joiners catch up on what has been run so far
+        if let Some(room) = self.shared_rooms.get(&msg.lobby_id) {
+            let history = serde_json::json!({
+                "type": "command_history",
+                "payload": { "commands": room.history_json() }
+            });
+            self.send_message(&history.to_string(), &msg.self_id);
+        }
+
+        self.send_roster(&msg.lobby_id, &msg.self_id);
+        if let Some(log) = self.chats.get_mut(&msg.lobby_id) {
+            let history = serde_json::json!({
+                "type": "chat_history",
+                "payload": { "roomId": msg.lobby_id, "messages": log.recent() }
+            });
+            self.send_message(&history.to_string(), &msg.self_id);
+        }
+        let joined = serde_json::json!({
+            "type": "member_joined",
+            "payload": { "roomId": msg.lobby_id, "member": self.member_info(&msg.self_id) }
+        });
+        for member in self.rooms.get(&msg.lobby_id).into_iter().flatten() {
+            if *member != msg.self_id {
+                self.send_message(&joined.to_string(), member);
+            }
+        }
     }
 }
 
@@ -247,23 +1585,73 @@ impl Handler<Disconnect> for Lobby {
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
         if self.sessions.remove(&msg.self_id).is_some() {
+            // Taken before the profile goes, for the other members
+            let left = serde_json::json!({
+                "type": "member_left",
+                "payload": { "roomId": msg.lobby_id, "member": self.member_info(&msg.self_id) }
+            });
+            self.binary_sockets.remove(&msg.self_id);
+            self.close_sockets.remove(&msg.self_id);
+            self.connected_at.remove(&msg.self_id);
+            self.last_active.remove(&msg.self_id);
+            self.expiry_warned.remove(&msg.self_id);
             // Remove current directory tracking
             self.curr_dir.remove(&msg.self_id);
+            self.prev_dir.remove(&msg.self_id);
+            self.dir_stack.remove(&msg.self_id);
+            self.profiles.remove(&msg.self_id);
+            self.session_rooms.remove(&msg.self_id);
+            self.jobs.remove_session(&msg.self_id);
+
+            // Anything not explicitly detached dies with its session; the rest
+            // stay tracked until they finish, so shutdown can still signal them
+            for command in self.running.remove(&msg.self_id).unwrap_or_default() {
+                if command.detached {
+                    self.orphaned.insert(command.id, command);
+                } else {
+                    exec::kill_pid_group(command.pid);
+                }
+            }
+            if let Some(cgroup) = self.cgroups.remove(&msg.self_id) {
+                // Removal waits for the killed processes to be reaped; keep that off the lobby
+                std::thread::spawn(move || cgroup.remove());
+            }
             
             if let Some(room_users) = self.rooms.get(&msg.lobby_id) {
                 room_users
                     .iter()
                     .filter(|conn_id| *conn_id.to_owned() != msg.self_id)
-                    .for_each(|user_id| {
-                        self.send_message(&format!("{} disconnected.", &msg.self_id), user_id)
-                    });
+                    .for_each(|user_id| self.send_message(&left.to_string(), user_id));
             }
 
+            let mut room_emptied = false;
             if let Some(lobby) = self.rooms.get_mut(&msg.lobby_id) {
                 if lobby.len() > 1 {
                     lobby.remove(&msg.self_id);
                 } else {
                     self.rooms.remove(&msg.lobby_id);
+                    self.chats.remove(&msg.lobby_id);
+                    room_emptied = true;
+                    if let Some(room) = self.directory.get_mut(&msg.lobby_id) {
+                        room.empty_since = Some(Utc::now());
+                    }
+                    // A shared room's shell goes with its last member
+                    if self.shared_rooms.remove(&msg.lobby_id).is_some() {
+                        self.curr_dir.remove(&msg.lobby_id);
+                        self.prev_dir.remove(&msg.lobby_id);
+                        self.dir_stack.remove(&msg.lobby_id);
+                    }
+                }
+            }
+
+            if !room_emptied {
+                let remaining = self.audience(&msg.lobby_id);
+                let changed = self
+                    .shared_rooms
+                    .get_mut(&msg.lobby_id)
+                    .is_some_and(|room| room.remove_member(&msg.self_id, &remaining));
+                if changed {
+                    self.broadcast_control(&msg.lobby_id, "left");
                 }
             }
         }
@@ -273,16 +1661,65 @@ impl Handler<Disconnect> for Lobby {
 impl Handler<ClientActorMessage> for Lobby {
     type Result = ();
 
-    fn handle(&mut self, msg: ClientActorMessage, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: ClientActorMessage, ctx: &mut Context<Self>) {
+        let _span = self.session_span(&msg.id).entered();
+        self.last_active.insert(msg.id, Utc::now());
+
         // Try to parse as JSON command
         if let Ok(parsed) = serde_json::from_str::<serde_json::Value>(&msg.msg) {
             if let Some(msg_type) = parsed["type"].as_str() {
+                if READ_ONLY_DENIED.contains(&msg_type) && self.profiles.get(&msg.id).is_some_and(|p| p.read_only) {
+                    self.send_error(&msg.id, msg_type, "this session is read-only");
+                    return;
+                }
+                if matches!(msg_type, "command" | "fanout") && self.draining.is_some() {
+                    self.send_error(&msg.id, msg_type, "the server is shutting down");
+                    return;
+                }
                 match msg_type {
                     "command" => {
-                        if let Some(command) = parsed["payload"]["command"].as_str() {
-                            self.execute_command(command, &msg.id);
-                            return;
+                        match serde_json::from_value::<CommandRequest>(parsed["payload"].clone()) {
+                            Ok(request) => self.execute_command(&request, &msg.id, ctx.address()),
+                            Err(e) => self.send_error(&msg.id, msg_type, &format!("invalid payload: {}", e)),
+                        }
+                        return;
+                    }
+                    "jobs" => {
+                        let user = self.profiles.get(&msg.id).and_then(|p| p.user.as_deref());
+                        let jobs: Vec<_> = self.jobs.list(&msg.id, user).iter().map(|j| j.to_json()).collect();
+                        let response = serde_json::json!({
+                            "type": "job_list",
+                            "payload": { "jobs": jobs }
+                        });
+                        self.send_message(&response.to_string(), &msg.id);
+                        return;
+                    }
+                    "job_attach" | "job_detach" | "job_kill" | "job_log" => {
+                        match serde_json::from_value::<JobRequest>(parsed["payload"].clone()) {
+                            Ok(request) => self.handle_job_request(msg_type, request, &msg.id),
+                            Err(e) => self.send_error(&msg.id, msg_type, &format!("invalid payload: {}", e)),
                         }
+                        return;
+                    }
+                    "chat_message" | "direct_message" => {
+                        self.handle_chat(msg_type, &parsed["payload"], &msg.id, &msg.room_id);
+                        return;
+                    }
+                    "fanout" => {
+                        self.handle_fanout(&parsed["payload"], &msg.id, ctx.address());
+                        return;
+                    }
+                    "list_members" => {
+                        self.send_roster(&msg.room_id, &msg.id);
+                        return;
+                    }
+                    "control_request" | "control_grant" | "control_deny" | "control_release" | "control_takeover" => {
+                        self.handle_control(msg_type, &parsed["payload"], &msg.id);
+                        return;
+                    }
+                    "schedule_add" | "schedules" | "schedule_remove" | "schedule_history" => {
+                        self.handle_schedule_request(msg_type, &parsed["payload"], &msg.id);
+                        return;
                     }
                     "ping" => {
                         // Respond with pong
@@ -295,28 +1732,451 @@ impl Handler<ClientActorMessage> for Lobby {
                         self.send_message(&pong_response.to_string(), &msg.id);
                         return;
                     }
+                    // Heartbeat replies and the frontend's hello on connect need no answer
+                    "pong" | "connection" => return,
                     _ => {
-                        // Handle other message types or echo back
-                        println!("Received message type: {}", msg_type);
+                        tracing::debug!(msg_type, "unknown message type");
+                        self.send_error(&msg.id, msg_type, "unknown message type");
+                        return;
                     }
                 }
             }
         }
 
-        // Handle legacy whisper and broadcast (keep for compatibility)
-        if msg.msg.starts_with("\\w") {
-            if let Some(id_to) = msg.msg.split(' ').nth(1) {
-                if let Ok(uuid) = Uuid::parse_str(id_to) {
-                    self.send_message(&msg.msg, &uuid);
+        self.send_error(&msg.id, "unknown", "messages must be JSON with a `type` and `payload`");
+    }
+}
+
+impl Handler<RoomJoin> for Lobby {
+    type Result = Result<Admission, RoomError>;
+
+    fn handle(&mut self, msg: RoomJoin, _: &mut Context<Self>) -> Self::Result {
+        let room = self.directory.resolve(&msg.room).ok_or(RoomError::NotFound)?;
+
+        // A signed invite stands in for the room's password and invite code
+        let mut claims: Option<InviteClaims> = None;
+        match msg.invite.as_deref() {
+            Some(token) if invite::is_signed(token) => {
+                let verified = self.invite_signer.verify(token).map_err(RoomError::Forbidden)?;
+                if verified.room != room.id {
+                    return Err(RoomError::Forbidden("invite is for another room".to_string()));
+                }
+                if self.invites.is_revoked(&verified.id) {
+                    return Err(RoomError::Forbidden("invite has been revoked".to_string()));
                 }
+                claims = Some(verified);
             }
-        } else {
-            // Broadcast to all users in the room
-            if let Some(room_users) = self.rooms.get(&msg.room_id) {
-                for client in room_users {
-                    self.send_message(&msg.msg, client);
+            invite => {
+                if !room.admits(msg.password.as_deref(), invite) {
+                    return Err(RoomError::Forbidden("wrong room password or invite".to_string()));
                 }
             }
         }
+
+        if self.sessions.len() >= self.config.max_sessions {
+            return Err(RoomError::Conflict(format!("server is full ({} sessions)", self.sessions.len())));
+        }
+        let members = self.rooms.get(&room.id).map_or(0, |m| m.len());
+        if room.max_members.is_some_and(|max| members >= max) {
+            return Err(RoomError::Conflict(format!("room is full ({} members)", members)));
+        }
+        Ok(Admission { room: room.id, invite: claims })
+    }
+}
+
+impl Handler<InviteCreate> for Lobby {
+    type Result = Result<serde_json::Value, RoomError>;
+
+    fn handle(&mut self, msg: InviteCreate, _: &mut Context<Self>) -> Self::Result {
+        let room = self.directory.resolve(&msg.room).ok_or(RoomError::NotFound)?;
+        if !room.managed_by(msg.user.as_deref(), msg.admin) {
+            return Err(RoomError::Forbidden("only the room's creator or an admin may invite".to_string()));
+        }
+        if msg.expires_in_secs == 0 || msg.expires_in_secs > invite::MAX_INVITE_SECS {
+            return Err(RoomError::Invalid(format!(
+                "expiresInSecs must be between 1 and {}",
+                invite::MAX_INVITE_SECS
+            )));
+        }
+
+        let claims = InviteClaims {
+            id: Uuid::new_v4(),
+            room: room.id,
+            role: msg.role,
+            expires_at: Utc::now().timestamp() + msg.expires_in_secs as i64,
+        };
+        let token = self.invite_signer.sign(&claims);
+        let slug = room.slug.clone();
+        let issued = IssuedInvite { claims, created_by: msg.user, created_at: Utc::now() };
+
+        let mut response = issued.to_json(false);
+        response["token"] = serde_json::json!(token);
+        response["path"] = serde_json::json!(format!("/ws/{}?invite={}", slug, token));
+        tracing::info!(invite_id = %issued.claims.id, room = %issued.claims.room, created_by = issued.created_by.as_deref(), "invite minted");
+        self.invites.record(issued);
+        Ok(response)
+    }
+}
+
+impl Handler<InviteList> for Lobby {
+    type Result = Vec<serde_json::Value>;
+
+    fn handle(&mut self, _: InviteList, _: &mut Context<Self>) -> Self::Result {
+        self.invites.list()
+    }
+}
+
+impl Handler<InviteRevoke> for Lobby {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: InviteRevoke, _: &mut Context<Self>) -> Self::Result {
+        if let Err(e) = self.invites.revoke(msg.invite_id) {
+            tracing::warn!(invite_id = %msg.invite_id, error = %e, "failed to save revoked invite");
+        }
+        tracing::info!(invite_id = %msg.invite_id, "invite revoked");
+        for (session, profile) in &self.profiles {
+            if profile.invite == Some(msg.invite_id) {
+                self.close_session(session, "invite revoked");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Handler<RoomCreate> for Lobby {
+    type Result = Result<serde_json::Value, RoomError>;
+
+    fn handle(&mut self, msg: RoomCreate, _: &mut Context<Self>) -> Self::Result {
+        let room = self.directory.create(msg.room, msg.created_by, &self.config.rooms)?;
+        tracing::info!(room = %room.id, slug = %room.slug, created_by = room.created_by.as_deref(), "room created");
+        let mut response = room.to_json(0);
+        // Only the creator ever sees the invite without asking for it
+        response["inviteToken"] = serde_json::json!(room.invite_token());
+        Ok(response)
+    }
+}
+
+impl Handler<RoomList> for Lobby {
+    type Result = Vec<serde_json::Value>;
+
+    fn handle(&mut self, _: RoomList, _: &mut Context<Self>) -> Self::Result {
+        self.directory
+            .list()
+            .into_iter()
+            .map(|r| r.to_json(self.rooms.get(&r.id).map_or(0, |m| m.len())))
+            .collect()
+    }
+}
+
+impl Handler<RoomInspect> for Lobby {
+    type Result = Result<serde_json::Value, RoomError>;
+
+    fn handle(&mut self, msg: RoomInspect, _: &mut Context<Self>) -> Self::Result {
+        let room = self.directory.resolve(&msg.room).ok_or(RoomError::NotFound)?;
+        let mut members: Vec<&Uuid> = self.rooms.get(&room.id).into_iter().flatten().collect();
+        members.sort_by_key(|s| self.connected_at.get(s));
+
+        let mut response = room.to_json(members.len());
+        response["roster"] = members.into_iter().map(|s| self.member_info(s)).collect();
+        response["shared"] = serde_json::json!(self.shared_rooms.contains_key(&room.id));
+        if room.managed_by(msg.user.as_deref(), msg.admin) {
+            response["inviteToken"] = serde_json::json!(room.invite_token());
+        }
+        Ok(response)
+    }
+}
+
+impl Handler<RoomClose> for Lobby {
+    type Result = Result<(), RoomError>;
+
+    fn handle(&mut self, msg: RoomClose, _: &mut Context<Self>) -> Self::Result {
+        let room = self.directory.resolve(&msg.room).ok_or(RoomError::NotFound)?;
+        if !room.managed_by(msg.user.as_deref(), msg.admin) {
+            return Err(RoomError::Forbidden("only the room's creator or an admin may close it".to_string()));
+        }
+        let id = room.id;
+        tracing::info!(room = %id, user = msg.user.as_deref(), "room closed");
+        self.close_room(&id, "closed");
+        Ok(())
+    }
+}
+
+impl Handler<FanoutFinished> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: FanoutFinished, _: &mut Context<Self>) {
+        let captured = msg.captured;
+        let mut notes = Vec::new();
+        if captured.timed_out {
+            notes.push("Command timed out".to_string());
+        }
+        if let Err(e) = &captured.status {
+            notes.push(format!("Process wait error: {}", e));
+        }
+        if captured.truncated {
+            notes.push(format!(
+                "Output truncated: command wrote {} bytes",
+                captured.stdout_bytes + captured.stderr_bytes
+            ));
+        }
+        let result = TargetResult {
+            exit: ExitReport::from_captured(&captured),
+            truncated: captured.truncated,
+            stdout: captured.stdout,
+            stderr: captured.stderr,
+            notes,
+        };
+        self.finish_fanout_target(msg.fanout_id, msg.index, result);
+    }
+}
+
+impl Handler<HealthProbe> for Lobby {
+    type Result = MessageResult<HealthProbe>;
+
+    fn handle(&mut self, _: HealthProbe, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(HealthReport { sessions: self.sessions.len(), rooms: self.directory.list().len() })
+    }
+}
+
+impl Handler<RefreshGauges> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, _: RefreshGauges, _: &mut Context<Self>) {
+        METRICS.sessions_active.set(self.sessions.len() as i64);
+        METRICS.rooms.set(self.directory.list().len() as i64);
+        let running = self.running.values().map(Vec::len).sum::<usize>() + self.orphaned.len() + self.execs.len();
+        METRICS.commands_running.set(running as i64);
+        METRICS.jobs_running.set(self.jobs.running_pids().count() as i64);
+    }
+}
+
+impl Handler<SessionList> for Lobby {
+    type Result = Vec<serde_json::Value>;
+
+    fn handle(&mut self, _: SessionList, _: &mut Context<Self>) -> Self::Result {
+        let mut sessions: Vec<&Uuid> = self.sessions.keys().collect();
+        sessions.sort_by_key(|s| self.connected_at.get(s));
+        sessions.into_iter().map(|s| self.session_json(s)).collect()
+    }
+}
+
+impl Handler<SessionInspect> for Lobby {
+    type Result = Option<serde_json::Value>;
+
+    fn handle(&mut self, msg: SessionInspect, _: &mut Context<Self>) -> Self::Result {
+        self.sessions.contains_key(&msg.session).then(|| self.session_json(&msg.session))
+    }
+}
+
+impl Handler<SessionDisconnect> for Lobby {
+    type Result = bool;
+
+    fn handle(&mut self, msg: SessionDisconnect, _: &mut Context<Self>) -> Self::Result {
+        if !self.sessions.contains_key(&msg.session) {
+            return false;
+        }
+        tracing::info!(session_id = %msg.session, reason = %msg.reason, "session disconnected by admin");
+        self.close_session(&msg.session, &msg.reason);
+        true
+    }
+}
+
+impl Handler<SessionKill> for Lobby {
+    type Result = Option<Result<usize, String>>;
+
+    fn handle(&mut self, msg: SessionKill, _: &mut Context<Self>) -> Self::Result {
+        if !self.sessions.contains_key(&msg.session) && !self.execs.contains_key(&msg.session) {
+            return None;
+        }
+        let signal_name = msg.signal.as_deref().unwrap_or("KILL");
+        #[cfg(unix)]
+        let Some(signal) = exec::parse_signal(signal_name) else {
+            return Some(Err(format!("unknown signal '{}'", signal_name)));
+        };
+
+        if let Some((_, command)) = self.execs.get(&msg.session) {
+            tracing::info!(exec_id = %msg.session, signal = signal_name, "exec signalled by admin");
+            #[cfg(unix)]
+            exec::signal_pid_group(command.pid, signal);
+            #[cfg(not(unix))]
+            exec::kill_pid_group(command.pid);
+            return Some(Ok(1));
+        }
+
+        let pids: Vec<u32> = self.running.get(&msg.session).into_iter().flatten().map(|c| c.pid).collect();
+        // Jobs among them should read as killed, as with `job_kill`
+        let user = self.profiles.get(&msg.session).and_then(|p| p.user.clone());
+        let job_ids: Vec<u32> = self
+            .jobs
+            .list(&msg.session, user.as_deref())
+            .iter()
+            .filter(|j| j.session == msg.session && pids.contains(&j.pid))
+            .map(|j| j.id)
+            .collect();
+        for id in job_ids {
+            if let Some(job) = self.jobs.get_mut(id) {
+                job.kill_requested = true;
+            }
+        }
+        for pid in &pids {
+            #[cfg(unix)]
+            exec::signal_pid_group(*pid, signal);
+            #[cfg(not(unix))]
+            exec::kill_pid_group(*pid);
+        }
+        tracing::info!(session_id = %msg.session, signal = signal_name, processes = pids.len(), "signalled session processes");
+        Some(Ok(pids.len()))
+    }
+}
+
+impl Handler<ExecCgroup> for Lobby {
+    type Result = Option<SessionCgroup>;
+
+    fn handle(&mut self, msg: ExecCgroup, _: &mut Context<Self>) -> Self::Result {
+        let root = self.cgroup_root.as_ref()?;
+        SessionCgroup::create(root, &msg.exec_id, &msg.limits)
+            .map_err(|e| tracing::warn!(exec_id = %msg.exec_id, error = %e, "failed to create cgroup"))
+            .ok()
+    }
+}
+
+impl Handler<ExecStarted> for Lobby {
+    type Result = bool;
+
+    fn handle(&mut self, msg: ExecStarted, _: &mut Context<Self>) -> Self::Result {
+        if self.draining.is_some() {
+            return false;
+        }
+        let id = self.next_id();
+        let command = RunningCommand {
+            id,
+            request_id: id,
+            pid: msg.pid,
+            detached: false,
+            command: msg.command,
+            started_at: Utc::now(),
+        };
+        self.execs.insert(msg.exec_id, (msg.user, command));
+        true
+    }
+}
+
+impl Handler<ExecFinished> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: ExecFinished, _: &mut Context<Self>) {
+        self.execs.remove(&msg.exec_id);
+    }
+}
+
+impl Handler<ExecList> for Lobby {
+    type Result = Vec<serde_json::Value>;
+
+    fn handle(&mut self, _: ExecList, _: &mut Context<Self>) -> Self::Result {
+        let mut execs: Vec<_> = self.execs.iter().collect();
+        execs.sort_by_key(|(_, (_, c))| c.started_at);
+        execs
+            .into_iter()
+            .map(|(id, (user, command))| {
+                let mut json = command.to_json();
+                json["execId"] = serde_json::json!(id);
+                json["user"] = serde_json::json!(user);
+                json
+            })
+            .collect()
+    }
+}
+
+impl Handler<Shutdown> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: Shutdown, ctx: &mut Context<Self>) {
+        if self.draining.is_some() {
+            return;
+        }
+        let deadline = Utc::now() + chrono::Duration::from_std(msg.grace).unwrap_or_default();
+        let notice = serde_json::json!({
+            "type": "server_shutting_down",
+            "payload": {
+                "deadline": deadline.to_rfc3339(),
+                "graceSecs": msg.grace.as_secs(),
+                "runningCommands": self.live_pids().len()
+            }
+        });
+        for session in self.sessions.keys() {
+            self.send_message(&notice.to_string(), session);
+        }
+
+        let tick = ctx.run_interval(DRAIN_TICK, |lobby, ctx| lobby.drain(ctx));
+        self.draining = Some(Draining {
+            deadline: Instant::now() + msg.grace,
+            termed_at: None,
+            killed_at: None,
+            tick,
+            done: Some(msg.done),
+        });
+        self.drain(ctx);
+    }
+}
+
+impl Handler<CommandFinished> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: CommandFinished, _: &mut Context<Self>) {
+        let _span = self.session_span(&msg.session).entered();
+        let _command = tracing::info_span!("command", request_id = msg.pending.result.request_id).entered();
+        self.finish_command(&msg.session, msg.pending, msg.captured);
+    }
+}
+
+impl Handler<JobOutput> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: JobOutput, _: &mut Context<Self>) {
+        let Some(job) = self.jobs.get_mut(msg.job_id) else {
+            return;
+        };
+        let sequence = job.record_output(msg.stream, &msg.data);
+
+        if job.attached {
+            let job = self.jobs.get(msg.job_id).expect("job looked up above");
+            self.send_job_output(&job.session, job, (sequence, msg.stream, &msg.data), job.attach_encoding);
+        }
+    }
+}
+
+impl Handler<JobLogFiles> for Lobby {
+    type Result = Option<Vec<PathBuf>>;
+
+    fn handle(&mut self, msg: JobLogFiles, _: &mut Context<Self>) -> Self::Result {
+        let job = self.jobs.get(msg.job_id).filter(|j| j.detached && j.owner.as_deref() == Some(msg.user.as_str()))?;
+        Some(self.jobs.log_files(job))
+    }
+}
+
+impl Handler<ScheduleFinished> for Lobby {
+    type Result = ();
+
+    fn handle(&mut self, msg: ScheduleFinished, _: &mut Context<Self>) {
+        let captured = msg.captured;
+        let exit = ExitReport::from_captured(&captured);
+        let (status, note) = match &captured.status {
+            _ if captured.timed_out => {
+                (RunStatus::TimedOut, Some(format!("Timed out after {} seconds", msg.timeout.as_secs())))
+            }
+            Ok(status) if status.success() => (RunStatus::Succeeded, None),
+            Ok(_) => (RunStatus::Failed, None),
+            Err(e) => (RunStatus::Failed, Some(format!("Process wait error: {}", e))),
+        };
+
+        let mut run = ScheduleRun::new(msg.started_at, status, exit, &captured.stdout, &captured.stderr);
+        run.notes.extend(note);
+        if captured.truncated {
+            run.notes.push(format!(
+                "Output truncated: command wrote {} bytes",
+                captured.stdout_bytes + captured.stderr_bytes
+            ));
+        }
+        self.finish_schedule_run(msg.schedule_id, run);
     }
-}
\ No newline at end of file
+}
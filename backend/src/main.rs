@@ -1,52 +1,106 @@
+mod api;
+mod chat;
+mod config;
+mod exec;
+mod fallback;
+mod fanout;
+mod health;
+mod invite;
+mod jobs;
+mod limits;
 mod lobby;
+mod logging;
+#[allow(non_snake_case)]
 mod webSocketNeo;
 mod message;
+mod metrics;
+mod room;
+mod schedule;
+mod shell;
+mod shutdown;
+#[allow(non_snake_case)]
 mod startConn;
 
+use config::ServerConfig;
 use lobby::Lobby;
+use message::Shutdown;
+use shutdown::Draining;
 use actix::Actor;
 use startConn::start_connection as start_connection_route;
-use actix_web::{App, HttpServer, middleware::Logger, web, HttpResponse, Result, middleware::DefaultHeaders};
-
-// Add health check endpoint
-async fn health_check() -> Result<HttpResponse> {
-    Ok(HttpResponse::Ok()
-        .insert_header(("Access-Control-Allow-Origin", "*"))
-        .insert_header(("Access-Control-Allow-Methods", "GET, POST, OPTIONS"))
-        .insert_header(("Access-Control-Allow-Headers", "Content-Type"))
-        .json(serde_json::json!({
-            "status": "healthy",
-            "service": "http-ssh-server",
-            "timestamp": chrono::Utc::now().timestamp(),
-            "websocket_available": true,
-            "websocket_endpoint": "/ws/{room_id}"
-        })))
-}
+use actix_web::{App, HttpServer, middleware::Logger, web, HttpResponse, middleware::DefaultHeaders};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    env_logger::init();
-    
-    println!("Starting HTTP-SSH Server on 127.0.0.1:8080");
-    println!("Health check endpoint: http://127.0.0.1:8080/health");
-    println!("WebSocket endpoint: ws://127.0.0.1:8080/ws/{{room_id}}");
+    let config = web::Data::new(ServerConfig::load()?);
+    logging::init(&config.log);
+
+    tracing::info!(addr = "127.0.0.1:8080", "starting HTTP-SSH server");
+    tracing::info!("health check endpoints: http://127.0.0.1:8080/health/live, /health/ready");
+    tracing::info!("WebSocket endpoint: ws://127.0.0.1:8080/ws/{{room_id}}");
+    tracing::info!(allowed = %config.allowed_shells.join(", "), default = %config.default_shell, "shells");
     
-    let chat_server = Lobby::default().start();
+    let chat_server = Lobby::new(config.clone().into_inner())?.start();
+    let draining = web::Data::new(Draining::default());
+    let started = web::Data::new(health::Started::now());
+    let http_sessions = web::Data::new(fallback::HttpSessions::default());
+    let grace = std::time::Duration::from_secs(config.shutdown_grace_secs);
+    let (lobby, flag) = (chat_server.clone(), draining.clone());
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .wrap(
                 DefaultHeaders::new()
                     .add(("Access-Control-Allow-Origin", "*"))
-                    .add(("Access-Control-Allow-Methods", "GET, POST, OPTIONS"))
-                    .add(("Access-Control-Allow-Headers", "Content-Type"))
+                    .add(("Access-Control-Allow-Methods", "GET, POST, DELETE, OPTIONS"))
+                    .add(("Access-Control-Allow-Headers", "Content-Type, Last-Event-ID"))
             )
-            .route("/health", web::get().to(health_check))
+            .route("/health", web::get().to(health::live))
+            .route("/health/live", web::get().to(health::live))
+            .route("/health/ready", web::get().to(health::ready))
+            .service(metrics::export)
             .service(start_connection_route)
+            .service(api::job_log)
+            .service(api::create_room)
+            .service(api::list_rooms)
+            .service(api::inspect_room)
+            .service(api::close_room)
+            .service(api::create_invite)
+            .service(api::list_invites)
+            .service(api::revoke_invite)
+            .service(api::list_sessions)
+            .service(api::inspect_session)
+            .service(api::disconnect_session)
+            .service(api::kill_session_processes)
+            .service(api::exec_command)
+            .service(fallback::open_session)
+            .service(fallback::send_message)
+            .service(fallback::session_events)
+            .service(fallback::close_session)
+            // CORS preflight for the JSON API; the headers come from `DefaultHeaders`
+            .route("/api/{tail:.*}", web::method(actix_web::http::Method::OPTIONS).to(HttpResponse::NoContent))
             .app_data(web::Data::new(chat_server.clone()))
+            .app_data(config.clone())
+            .app_data(draining.clone())
+            .app_data(started.clone())
+            .app_data(http_sessions.clone())
     })
     .bind("127.0.0.1:8080")?
-    .run()
-    .await
+    // Signals are handled below so sessions and commands get drained first
+    .disable_signals()
+    .run();
+
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown::signal().await;
+        tracing::info!(grace_secs = grace.as_secs(), "shutting down, draining sessions");
+        flag.start();
+        let (done, drained) = tokio::sync::oneshot::channel();
+        if lobby.send(Shutdown { grace, done }).await.is_ok() {
+            let _ = drained.await;
+        }
+        handle.stop(true).await;
+    });
+
+    server.await
 }
@@ -1,4 +1,14 @@
+use crate::config::SessionProfile;
+use crate::exec::{Captured, PendingCommand, Stream};
+use crate::invite::{InviteClaims, InviteRole};
+use crate::limits::{ResourceLimits, SessionCgroup};
+use crate::room::{NewRoom, RoomError};
 use actix::prelude::{Message, Recipient};
+use actix_web_actors::ws::CloseCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
 use uuid::Uuid; // Changed from vvid::Vvid
 
 #[derive(Message)]
@@ -7,12 +17,41 @@ pub struct WsMessage {
     pub message: String, // Changed from tuple struct to named field
 }
 
+/// Raw bytes for a WebSocket binary frame
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct WsBinaryMessage {
+    pub data: Vec<u8>,
+}
+
+/// Close a client's socket, telling it why
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct CloseSession {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+/// Drain sessions and processes, then close every socket and answer on `done`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Shutdown {
+    /// How long running commands get to finish before they are signalled
+    pub grace: Duration,
+    pub done: tokio::sync::oneshot::Sender<()>,
+}
+
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub addr: Recipient<WsMessage>,
+    pub binary_addr: Recipient<WsBinaryMessage>,
+    pub close_addr: Recipient<CloseSession>,
     pub lobby_id: Uuid,
     pub self_id: Uuid,
+    pub profile: SessionProfile,
+    /// Join the room as a shared terminal rather than a private shell
+    pub shared: bool,
 }
 
 #[derive(Message)]
@@ -30,11 +69,310 @@ pub struct ClientActorMessage {
     pub room_id: Uuid,
 }
 
-// Add command message type
+/// A command's worker thread reporting back to the lobby
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct CommandMessage {
-    pub id: Uuid,
+pub struct CommandFinished {
+    pub session: Uuid,
+    pub pending: PendingCommand,
+    pub captured: Captured,
+}
+
+/// Live output from a background job's worker thread
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct JobOutput {
+    pub job_id: u32,
+    pub stream: Stream,
+    pub data: Vec<u8>,
+}
+
+/// Payload of a client `command` message
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandRequest {
     pub command: String,
-    pub room_id: Uuid,
+    /// Echoed back on every output frame; assigned by the server if absent
+    #[serde(default)]
+    pub request_id: Option<u32>,
+    #[serde(default)]
+    pub output_encoding: OutputEncoding,
+    /// Lower the server's output cap for this command
+    #[serde(default)]
+    pub max_output_bytes: Option<u64>,
+    /// Override the default timeout, up to the role's maximum
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    /// Run without a timeout and keep running after the client leaves
+    #[serde(default)]
+    pub detached: bool,
+    /// Start as a background job instead of waiting for the output
+    #[serde(default)]
+    pub background: bool,
+}
+
+/// Payload of `job_attach`, `job_detach`, `job_kill` and `job_log`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct JobRequest {
+    pub job_id: u32,
+    /// Signal for `job_kill`, e.g. "TERM" (the default) or "KILL"
+    #[serde(default)]
+    pub signal: Option<String>,
+    /// Encoding for `job_attach` and `job_log` output
+    #[serde(default)]
+    pub output_encoding: OutputEncoding,
+}
+
+/// A scheduled command's worker is done
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ScheduleFinished {
+    pub schedule_id: u32,
+    pub started_at: DateTime<Utc>,
+    pub timeout: Duration,
+    pub captured: Captured,
+}
+
+/// Payload of `fanout`: one command for several sessions' or rooms' shells
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FanoutRequest {
+    pub command: String,
+    #[serde(default)]
+    pub sessions: Vec<Uuid>,
+    /// Room ids or slugs; each member's shell is a target
+    #[serde(default)]
+    pub rooms: Vec<String>,
+    #[serde(default)]
+    pub timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub request_id: Option<u32>,
+}
+
+/// One fan-out copy's worker is done
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct FanoutFinished {
+    pub fanout_id: u32,
+    /// Place of the target in the fan-out's list
+    pub index: usize,
+    pub captured: Captured,
+}
+
+/// Payload of `schedule_add`; exactly one of `cron` and `at` is set
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleAdd {
+    pub command: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Five-field cron expression, evaluated in UTC
+    #[serde(default)]
+    pub cron: Option<String>,
+    /// One-shot run time, RFC 3339
+    #[serde(default)]
+    pub at: Option<DateTime<Utc>>,
+}
+
+/// Payload of `schedule_remove` and `schedule_history`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleRef {
+    pub schedule_id: u32,
+}
+
+/// Ask the lobby for a detached job's log files, on behalf of `user`
+#[derive(Message)]
+#[rtype(result = "Option<Vec<PathBuf>>")]
+pub struct JobLogFiles {
+    pub job_id: u32,
+    pub user: String,
+}
+
+/// Check a joiner may enter a room, by id or slug, and resolve it to its id
+#[derive(Message)]
+#[rtype(result = "Result<Admission, RoomError>")]
+pub struct RoomJoin {
+    pub room: String,
+    pub password: Option<String>,
+    /// The room's invite code, or a signed invite link token
+    pub invite: Option<String>,
+}
+
+pub struct Admission {
+    pub room: Uuid,
+    /// Set when the joiner came in on a signed invite
+    pub invite: Option<InviteClaims>,
+}
+
+/// Mint a signed invite to a room, if `user` created it or is an admin
+#[derive(Message)]
+#[rtype(result = "Result<serde_json::Value, RoomError>")]
+pub struct InviteCreate {
+    pub room: String,
+    pub role: InviteRole,
+    pub expires_in_secs: u64,
+    pub user: Option<String>,
+    pub admin: bool,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<serde_json::Value>")]
+pub struct InviteList;
+
+/// Revoke an invite and disconnect everyone who joined with it
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct InviteRevoke {
+    pub invite_id: Uuid,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<serde_json::Value, RoomError>")]
+pub struct RoomCreate {
+    pub room: NewRoom,
+    pub created_by: Option<String>,
+}
+
+#[derive(Message)]
+#[rtype(result = "Vec<serde_json::Value>")]
+pub struct RoomList;
+
+/// A room's details and roster; `user` and `admin` decide whether its invite token is shown
+#[derive(Message)]
+#[rtype(result = "Result<serde_json::Value, RoomError>")]
+pub struct RoomInspect {
+    pub room: String,
+    pub user: Option<String>,
+    pub admin: bool,
+}
+
+/// Close a room and disconnect everyone in it, if `user` created it or is an admin
+#[derive(Message)]
+#[rtype(result = "Result<(), RoomError>")]
+pub struct RoomClose {
+    pub room: String,
+    pub user: Option<String>,
+    pub admin: bool,
+}
+
+/// Events an HTTP session has buffered since `after`, for a poll or an SSE stream
+#[derive(Message)]
+#[rtype(result = "PollResult")]
+pub struct Poll {
+    pub after: u64,
+}
+
+pub struct PollResult {
+    /// `(id, JSON text)`, oldest first
+    pub events: Vec<(u64, String)>,
+    /// Events after `after` were dropped before this poll came
+    pub missed: bool,
+    /// The session has ended; these are its last events
+    pub closed: bool,
+    /// Changes once more events are buffered
+    pub changes: tokio::sync::watch::Receiver<u64>,
+}
+
+/// A message from an HTTP client, as a WebSocket client would send it.
+/// Answers false if the session has already closed.
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct ClientSend {
+    pub text: String,
+}
+
+/// The HTTP client is done with its session
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Hangup;
+
+/// Counts for `/health/ready`; answering at all shows the lobby isn't stuck
+#[derive(Message)]
+#[rtype(result = "HealthReport")]
+pub struct HealthProbe;
+
+pub struct HealthReport {
+    pub sessions: usize,
+    pub rooms: usize,
+}
+
+/// Bring the lobby's gauges up to date before `/metrics` is scraped
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct RefreshGauges;
+
+/// Every connected session, for the admin API
+#[derive(Message)]
+#[rtype(result = "Vec<serde_json::Value>")]
+pub struct SessionList;
+
+#[derive(Message)]
+#[rtype(result = "Option<serde_json::Value>")]
+pub struct SessionInspect {
+    pub session: Uuid,
+}
+
+/// Close a session's socket; false if there is no such session
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct SessionDisconnect {
+    pub session: Uuid,
+    pub reason: String,
+}
+
+/// Signal every process a session has running, detached ones included. The
+/// id may also be a `POST /api/exec` command's. Returns how many were
+/// signalled, or `None` if there is no such session.
+#[derive(Message)]
+#[rtype(result = "Option<Result<usize, String>>")]
+pub struct SessionKill {
+    pub session: Uuid,
+    /// e.g. "TERM" or "KILL" (the default)
+    pub signal: Option<String>,
+}
+
+/// A transient cgroup for one `POST /api/exec` command, or `None` when cgroup
+/// limits are disabled. The caller attaches it before spawning and removes it after.
+#[derive(Message)]
+#[rtype(result = "Option<SessionCgroup>")]
+pub struct ExecCgroup {
+    pub exec_id: Uuid,
+    pub limits: ResourceLimits,
+}
+
+/// A `POST /api/exec` command has started; false if the server is shutting
+/// down, in which case the caller kills it
+#[derive(Message)]
+#[rtype(result = "bool")]
+pub struct ExecStarted {
+    pub exec_id: Uuid,
+    pub pid: u32,
+    pub user: Option<String>,
+    pub command: String,
+}
+
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ExecFinished {
+    pub exec_id: Uuid,
+}
+
+/// Every `POST /api/exec` command still running, for the admin API
+#[derive(Message)]
+#[rtype(result = "Vec<serde_json::Value>")]
+pub struct ExecList;
+
+/// How a client wants `stdout`/`stderr` delivered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    /// JSON strings, with invalid UTF-8 replaced
+    #[default]
+    Text,
+    /// Exact bytes as base64 strings in the JSON
+    Base64,
+    /// Exact bytes in binary frames ahead of the `command_output` message
+    Binary,
 }
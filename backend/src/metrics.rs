@@ -0,0 +1,121 @@
+use crate::exec::{ExitKind, ExitReport};
+use crate::lobby::Lobby;
+use crate::message::RefreshGauges;
+use actix::Addr;
+use actix_web::{get, web, HttpResponse, Responder};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use std::sync::LazyLock;
+
+/// Command durations run from a few milliseconds to hours-long jobs
+const DURATION_BUCKETS: &[f64] = &[0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+pub static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Everything exported on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    pub sessions_active: IntGauge,
+    pub rooms: IntGauge,
+    pub commands_running: IntGauge,
+    pub jobs_running: IntGauge,
+    /// By where the command came from and how it ended
+    pub commands: IntCounterVec,
+    pub command_duration: HistogramVec,
+    /// Output read from commands, by stream
+    pub output_bytes: IntCounterVec,
+    /// Bytes written to WebSockets, by frame type
+    pub ws_sent_bytes: IntCounterVec,
+    /// Rejected tokens, passwords and invites, by endpoint
+    pub auth_failures: IntCounterVec,
+    pub heartbeat_timeouts: IntCounter,
+    pub ws_protocol_errors: IntCounter,
+}
+
+impl Metrics {
+    fn new() -> Metrics {
+        let registry = Registry::new_custom(Some("http_ssh".to_string()), None).expect("valid prefix");
+        let metrics = Metrics {
+            sessions_active: IntGauge::new("sessions_active", "Connected WebSocket sessions").unwrap(),
+            rooms: IntGauge::new("rooms", "Open rooms").unwrap(),
+            commands_running: IntGauge::new("commands_running", "Commands still executing in sessions").unwrap(),
+            jobs_running: IntGauge::new("jobs_running", "Background jobs still running").unwrap(),
+            commands: IntCounterVec::new(
+                Opts::new("commands_total", "Commands that finished, by source and status"),
+                &["source", "status"],
+            )
+            .unwrap(),
+            command_duration: HistogramVec::new(
+                HistogramOpts::new("command_duration_seconds", "How long commands ran, by source")
+                    .buckets(DURATION_BUCKETS.to_vec()),
+                &["source"],
+            )
+            .unwrap(),
+            output_bytes: IntCounterVec::new(
+                Opts::new("output_bytes_total", "Bytes of command output read, by stream"),
+                &["stream"],
+            )
+            .unwrap(),
+            ws_sent_bytes: IntCounterVec::new(
+                Opts::new("ws_sent_bytes_total", "Bytes sent to WebSocket clients, by frame type"),
+                &["frame"],
+            )
+            .unwrap(),
+            auth_failures: IntCounterVec::new(
+                Opts::new("auth_failures_total", "Rejected credentials, by endpoint"),
+                &["endpoint"],
+            )
+            .unwrap(),
+            heartbeat_timeouts: IntCounter::new("heartbeat_timeouts_total", "Sessions dropped for missing heartbeats")
+                .unwrap(),
+            ws_protocol_errors: IntCounter::new("ws_protocol_errors_total", "WebSocket protocol errors").unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 11] = [
+            Box::new(metrics.sessions_active.clone()),
+            Box::new(metrics.rooms.clone()),
+            Box::new(metrics.commands_running.clone()),
+            Box::new(metrics.jobs_running.clone()),
+            Box::new(metrics.commands.clone()),
+            Box::new(metrics.command_duration.clone()),
+            Box::new(metrics.output_bytes.clone()),
+            Box::new(metrics.ws_sent_bytes.clone()),
+            Box::new(metrics.auth_failures.clone()),
+            Box::new(metrics.heartbeat_timeouts.clone()),
+            Box::new(metrics.ws_protocol_errors.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).expect("metric names are unique");
+        }
+        metrics
+    }
+
+    /// Count a finished command; `source` is `command`, `job`, `schedule`, `fanout` or `exec`
+    pub fn record_exit(&self, source: &str, exit: &ExitReport) {
+        let status = match (exit.kind, exit.code) {
+            (ExitKind::Exited, Some(0)) => "success",
+            (ExitKind::Exited, _) => "failure",
+            (kind, _) => kind.as_str(),
+        };
+        self.commands.with_label_values(&[source, status]).inc();
+        if exit.kind != ExitKind::SpawnFailed {
+            self.command_duration.with_label_values(&[source]).observe(exit.duration.as_secs_f64());
+        }
+    }
+
+    pub fn auth_failed(&self, endpoint: &str) {
+        self.auth_failures.with_label_values(&[endpoint]).inc();
+    }
+}
+
+/// Prometheus text exposition, with the gauges read fresh from the lobby
+#[get("/metrics")]
+pub async fn export(lobby: web::Data<Addr<Lobby>>) -> impl Responder {
+    let _ = lobby.send(RefreshGauges).await;
+
+    let mut body = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut body) {
+        return HttpResponse::InternalServerError().body(e.to_string());
+    }
+    HttpResponse::Ok().content_type(prometheus::TEXT_FORMAT).body(body)
+}
@@ -0,0 +1,384 @@
+use crate::shell::Shell;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+/// Commands kept in a shared room's history
+const HISTORY_KEPT: usize = 500;
+const MAX_SLUG_LEN: usize = 64;
+
+/// Limits for rooms created through the API
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RoomConfig {
+    /// How long a room may sit empty before it is closed, unless it sets its own
+    pub idle_expiry_secs: u64,
+    pub max_rooms: usize,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        RoomConfig {
+            idle_expiry_secs: 60 * 60,
+            max_rooms: 1000,
+        }
+    }
+}
+
+/// Why a room request was turned down
+#[derive(Debug)]
+pub enum RoomError {
+    NotFound,
+    /// Wrong password or invite, or not the caller's room
+    Forbidden(String),
+    /// Slug taken, room full or too many rooms
+    Conflict(String),
+    Invalid(String),
+}
+
+impl std::fmt::Display for RoomError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomError::NotFound => write!(f, "no such room"),
+            RoomError::Forbidden(m) | RoomError::Conflict(m) | RoomError::Invalid(m) => write!(f, "{}", m),
+        }
+    }
+}
+
+/// Settings for a new room, as sent to `POST /api/rooms`
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct NewRoom {
+    pub name: Option<String>,
+    /// Defaults to one made from the name
+    pub slug: Option<String>,
+    pub password: Option<String>,
+    /// Require the invite token handed back on creation
+    pub invite_only: bool,
+    pub max_members: Option<usize>,
+    pub idle_expiry_secs: Option<u64>,
+}
+
+/// A room that sessions may join by id or slug
+pub struct Room {
+    pub id: Uuid,
+    pub name: String,
+    pub slug: String,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+    password: Option<String>,
+    invite_token: Option<String>,
+    pub max_members: Option<usize>,
+    pub idle_expiry_secs: u64,
+    /// When the last member left, or the room was created; `None` while occupied
+    pub empty_since: Option<DateTime<Utc>>,
+}
+
+impl Room {
+    /// Whether a joiner knows the room's password or invite token, if it has either
+    pub fn admits(&self, password: Option<&str>, invite: Option<&str>) -> bool {
+        // Compared in constant time, so response timing doesn't leak the secret
+        let matches = |secret: &Option<String>, given: Option<&str>| match (secret, given) {
+            (None, _) => true,
+            (Some(secret), Some(given)) => secret.as_bytes().ct_eq(given.as_bytes()).into(),
+            (Some(_), None) => false,
+        };
+        matches(&self.password, password) && matches(&self.invite_token, invite)
+    }
+
+    pub fn invite_token(&self) -> Option<&str> {
+        self.invite_token.as_deref()
+    }
+
+    /// Whether the creator (or an admin) is asking; they may see and close the room.
+    /// A room without a creator was made anonymously, on a server with no users,
+    /// so any anonymous caller may manage it.
+    pub fn managed_by(&self, user: Option<&str>, admin: bool) -> bool {
+        admin || self.created_by.as_deref() == user
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.empty_since
+            .is_some_and(|t| (now - t).num_seconds() >= self.idle_expiry_secs as i64)
+    }
+
+    pub fn to_json(&self, members: usize) -> serde_json::Value {
+        serde_json::json!({
+            "roomId": self.id,
+            "name": self.name,
+            "slug": self.slug,
+            "createdBy": self.created_by,
+            "createdAt": self.created_at.to_rfc3339(),
+            "hasPassword": self.password.is_some(),
+            "inviteOnly": self.invite_token.is_some(),
+            "maxMembers": self.max_members,
+            "members": members,
+            "idleExpirySecs": self.idle_expiry_secs,
+            "emptySince": self.empty_since.map(|t| t.to_rfc3339())
+        })
+    }
+}
+
+/// Lowercase letters, digits and single dashes, e.g. "Team Build #2" -> "team-build-2"
+fn slugify(name: &str) -> String {
+    let mut slug = String::new();
+    for c in name.trim().chars().flat_map(char::to_lowercase) {
+        if c.is_ascii_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    slug.trim_end_matches('-').chars().take(MAX_SLUG_LEN).collect()
+}
+
+/// Every room that exists, by id and by slug
+#[derive(Default)]
+pub struct RoomDirectory {
+    rooms: HashMap<Uuid, Room>,
+    slugs: HashMap<String, Uuid>,
+}
+
+impl RoomDirectory {
+    pub fn create(&mut self, new: NewRoom, created_by: Option<String>, config: &RoomConfig) -> Result<&Room, RoomError> {
+        if self.rooms.len() >= config.max_rooms {
+            return Err(RoomError::Conflict(format!("at most {} rooms", config.max_rooms)));
+        }
+        if new.max_members == Some(0) {
+            return Err(RoomError::Invalid("maxMembers must be at least 1".to_string()));
+        }
+
+        let id = Uuid::new_v4();
+        let name = new
+            .name
+            .map(|n| n.trim().to_string())
+            .filter(|n| !n.is_empty())
+            .unwrap_or_else(|| format!("room-{}", &id.simple().to_string()[..8]));
+        let slug = slugify(new.slug.as_deref().unwrap_or(&name));
+        // Slugs share the path segment with ids, so one must never parse as the other
+        if slug.is_empty() || Uuid::parse_str(&slug).is_ok() {
+            return Err(RoomError::Invalid(format!("'{}' can't be used as a slug", slug)));
+        }
+        if self.slugs.contains_key(&slug) {
+            return Err(RoomError::Conflict(format!("slug '{}' is taken", slug)));
+        }
+
+        let now = Utc::now();
+        self.slugs.insert(slug.clone(), id);
+        self.rooms.insert(id, Room {
+            id,
+            name,
+            slug,
+            created_by,
+            created_at: now,
+            password: new.password.filter(|p| !p.is_empty()),
+            invite_token: new.invite_only.then(|| Uuid::new_v4().simple().to_string()),
+            max_members: new.max_members,
+            idle_expiry_secs: new.idle_expiry_secs.unwrap_or(config.idle_expiry_secs),
+            empty_since: Some(now),
+        });
+        Ok(&self.rooms[&id])
+    }
+
+    /// Look a room up by its id or its slug
+    pub fn resolve(&self, id_or_slug: &str) -> Option<&Room> {
+        let id = Uuid::parse_str(id_or_slug).ok().or_else(|| self.slugs.get(id_or_slug).copied())?;
+        self.rooms.get(&id)
+    }
+
+    pub fn get(&self, id: &Uuid) -> Option<&Room> {
+        self.rooms.get(id)
+    }
+
+    pub fn get_mut(&mut self, id: &Uuid) -> Option<&mut Room> {
+        self.rooms.get_mut(id)
+    }
+
+    pub fn list(&self) -> Vec<&Room> {
+        let mut rooms: Vec<&Room> = self.rooms.values().collect();
+        rooms.sort_by_key(|r| r.created_at);
+        rooms
+    }
+
+    pub fn remove(&mut self, id: &Uuid) -> Option<Room> {
+        let room = self.rooms.remove(id)?;
+        self.slugs.remove(&room.slug);
+        Some(room)
+    }
+
+    /// Rooms that have sat empty past their expiry
+    pub fn expired(&self, now: DateTime<Utc>) -> Vec<Uuid> {
+        self.rooms.values().filter(|r| r.is_expired(now)).map(|r| r.id).collect()
+    }
+}
+
+/// One command run in a shared room
+pub struct HistoryEntry {
+    pub request_id: u32,
+    pub command: String,
+    pub session: Uuid,
+    pub user: Option<String>,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub exit_code: Option<i32>,
+}
+
+impl HistoryEntry {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "requestId": self.request_id,
+            "command": self.command,
+            "runBy": { "sessionId": self.session, "user": self.user },
+            "startedAt": self.started_at.to_rfc3339(),
+            "finishedAt": self.finished_at.map(|t| t.to_rfc3339()),
+            "exitCode": self.exit_code
+        })
+    }
+}
+
+/// A room whose members all watch one shell: one working directory, one
+/// output stream and one command history. Only the driver may type.
+pub struct SharedRoom {
+    pub shell: Shell,
+    /// Member who may force a takeover; starts as the room's creator
+    pub owner: Uuid,
+    /// Member holding the input lock, if anyone does
+    pub driver: Option<Uuid>,
+    /// Watchers waiting on the driver to grant them control
+    pub control_requests: Vec<Uuid>,
+    history: VecDeque<HistoryEntry>,
+}
+
+impl SharedRoom {
+    pub fn new(shell: Shell, owner: Uuid) -> SharedRoom {
+        SharedRoom {
+            shell,
+            owner,
+            driver: Some(owner),
+            control_requests: Vec::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn can_type(&self, session: &Uuid) -> bool {
+        self.driver == Some(*session)
+    }
+
+    /// Hand the input lock to `session`, dropping its pending request
+    pub fn set_driver(&mut self, session: Option<Uuid>) {
+        self.driver = session;
+        self.control_requests.retain(|s| Some(*s) != session);
+    }
+
+    /// Forget a member who left. Returns whether the driver or owner changed.
+    pub fn remove_member(&mut self, session: &Uuid, remaining: &[Uuid]) -> bool {
+        self.control_requests.retain(|s| s != session);
+        let mut changed = false;
+        if self.driver == Some(*session) {
+            self.driver = None;
+            changed = true;
+        }
+        if self.owner == *session {
+            // Ownership passes to the driver, or failing that anyone still here
+            if let Some(next) = self.driver.or_else(|| remaining.first().copied()) {
+                self.owner = next;
+            }
+            changed = true;
+        }
+        changed
+    }
+
+    pub fn record_start(&mut self, request_id: u32, command: &str, session: Uuid, user: Option<String>) {
+        self.history.push_back(HistoryEntry {
+            request_id,
+            command: command.to_string(),
+            session,
+            user,
+            started_at: Utc::now(),
+            finished_at: None,
+            exit_code: None,
+        });
+        while self.history.len() > HISTORY_KEPT {
+            self.history.pop_front();
+        }
+    }
+
+    pub fn record_finish(&mut self, request_id: u32, exit_code: i32) {
+        if let Some(entry) = self.history.iter_mut().rev().find(|e| e.request_id == request_id) {
+            entry.finished_at = Some(Utc::now());
+            entry.exit_code = Some(exit_code);
+        }
+    }
+
+    pub fn history_json(&self) -> Vec<serde_json::Value> {
+        self.history.iter().map(|e| e.to_json()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room(directory: &mut RoomDirectory, new: NewRoom, created_by: Option<&str>) -> Uuid {
+        directory.create(new, created_by.map(str::to_string), &RoomConfig::default()).unwrap().id
+    }
+
+    #[test]
+    fn admits_checks_password_and_invite() {
+        let mut directory = RoomDirectory::default();
+        let open = room(&mut directory, NewRoom::default(), None);
+        let locked = room(&mut directory, NewRoom { password: Some("pw".to_string()), ..NewRoom::default() }, None);
+        let invited = room(&mut directory, NewRoom { invite_only: true, ..NewRoom::default() }, None);
+
+        assert!(directory.get(&open).unwrap().admits(None, None));
+        assert!(directory.get(&open).unwrap().admits(Some("anything"), None));
+
+        let locked = directory.get(&locked).unwrap();
+        assert!(locked.admits(Some("pw"), None));
+        assert!(!locked.admits(Some("p"), None));
+        assert!(!locked.admits(Some("pw2"), None));
+        assert!(!locked.admits(None, None));
+
+        let invited = directory.get(&invited).unwrap();
+        let token = invited.invite_token().unwrap().to_string();
+        assert!(invited.admits(None, Some(&token)));
+        assert!(!invited.admits(None, Some("wrong")));
+        assert!(!invited.admits(None, None));
+    }
+
+    #[test]
+    fn managed_by_creator_or_admin() {
+        let mut directory = RoomDirectory::default();
+        let owned = room(&mut directory, NewRoom::default(), Some("alice"));
+        let owned = directory.get(&owned).unwrap();
+        assert!(owned.managed_by(Some("alice"), false));
+        assert!(!owned.managed_by(Some("bob"), false));
+        assert!(!owned.managed_by(None, false));
+        assert!(owned.managed_by(Some("bob"), true));
+    }
+
+    #[test]
+    fn rooms_without_a_creator_are_managed_anonymously() {
+        let mut directory = RoomDirectory::default();
+        let anonymous = room(&mut directory, NewRoom::default(), None);
+        let anonymous = directory.get(&anonymous).unwrap();
+        assert!(anonymous.managed_by(None, false));
+        assert!(!anonymous.managed_by(Some("alice"), false));
+        assert!(anonymous.managed_by(Some("alice"), true));
+    }
+
+    #[test]
+    fn slugs_resolve_and_stay_unique() {
+        let mut directory = RoomDirectory::default();
+        let id = room(&mut directory, NewRoom { name: Some("Team Build #2".to_string()), ..NewRoom::default() }, None);
+        assert_eq!(directory.resolve("team-build-2").unwrap().id, id);
+        assert_eq!(directory.resolve(&id.to_string()).unwrap().id, id);
+
+        let taken = NewRoom { slug: Some("team-build-2".to_string()), ..NewRoom::default() };
+        assert!(matches!(directory.create(taken, None, &RoomConfig::default()), Err(RoomError::Conflict(_))));
+        let uuid_slug = NewRoom { slug: Some(Uuid::new_v4().to_string()), ..NewRoom::default() };
+        assert!(matches!(directory.create(uuid_slug, None, &RoomConfig::default()), Err(RoomError::Invalid(_))));
+    }
+}
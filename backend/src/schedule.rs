@@ -0,0 +1,328 @@
+use crate::exec::ExitReport;
+use chrono::{DateTime, Utc};
+use croner::Cron;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Runs kept per schedule
+const HISTORY_KEPT: usize = 20;
+/// Output kept per stream for each run in the history
+const HISTORY_OUTPUT_BYTES: usize = 16 * 1024;
+const SCHEDULES_PER_USER: usize = 50;
+
+/// When a schedule fires. Cron expressions are standard five-field ones, in UTC.
+pub enum When {
+    Cron(Box<Cron>),
+    Once(DateTime<Utc>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    Succeeded,
+    Failed,
+    TimedOut,
+    SpawnFailed,
+}
+
+impl RunStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RunStatus::Succeeded => "succeeded",
+            RunStatus::Failed => "failed",
+            RunStatus::TimedOut => "timedOut",
+            RunStatus::SpawnFailed => "spawnFailed",
+        }
+    }
+}
+
+/// One finished run of a schedule
+pub struct ScheduleRun {
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    pub status: RunStatus,
+    pub exit: ExitReport,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+    pub notes: Vec<String>,
+}
+
+impl ScheduleRun {
+    /// Keep only the end of the output, which is where failures usually show
+    pub fn new(started_at: DateTime<Utc>, status: RunStatus, exit: ExitReport, stdout: &[u8], stderr: &[u8]) -> ScheduleRun {
+        let tail = |bytes: &[u8]| bytes[bytes.len().saturating_sub(HISTORY_OUTPUT_BYTES)..].to_vec();
+        ScheduleRun {
+            started_at,
+            finished_at: Utc::now(),
+            status,
+            exit,
+            stdout: tail(stdout),
+            stderr: tail(stderr),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "startedAt": self.started_at.to_rfc3339(),
+            "finishedAt": self.finished_at.to_rfc3339(),
+            "durationMs": (self.finished_at - self.started_at).num_milliseconds(),
+            "status": self.status.as_str(),
+            "exitCode": self.exit.code,
+            "exit": self.exit.to_json(),
+            "stdout": String::from_utf8_lossy(&self.stdout),
+            "stderr": String::from_utf8_lossy(&self.stderr),
+            "notes": self.notes
+        })
+    }
+}
+
+/// A command a user asked the server to run later, once or repeatedly
+pub struct Schedule {
+    pub id: u32,
+    pub owner: String,
+    pub name: Option<String>,
+    pub command: String,
+    pub when: When,
+    pub created_at: DateTime<Utc>,
+    /// `None` once a one-shot schedule has fired
+    pub next_run: Option<DateTime<Utc>>,
+    /// Set while a run is in progress; a due run is skipped rather than overlapped
+    pub running_pid: Option<u32>,
+    history: VecDeque<ScheduleRun>,
+}
+
+impl Schedule {
+    pub fn history(&self) -> impl Iterator<Item = &ScheduleRun> {
+        self.history.iter()
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let (cron, at) = match &self.when {
+            When::Cron(cron) => (Some(cron.as_str().to_string()), None),
+            When::Once(at) => (None, Some(at.to_rfc3339())),
+        };
+        serde_json::json!({
+            "scheduleId": self.id,
+            "name": self.name,
+            "command": self.command,
+            "cron": cron,
+            "at": at,
+            "createdAt": self.created_at.to_rfc3339(),
+            "nextRun": self.next_run.map(|t| t.to_rfc3339()),
+            "running": self.running_pid.is_some(),
+            "lastRun": self.history.back().map(|r| r.to_json())
+        })
+    }
+
+    /// Will never run again, e.g. a one-shot that has fired
+    pub fn is_spent(&self) -> bool {
+        self.next_run.is_none() && self.running_pid.is_none()
+    }
+
+    fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        match &self.when {
+            When::Cron(cron) => cron.find_next_occurrence(&after, false).ok(),
+            When::Once(_) => None,
+        }
+    }
+}
+
+/// Every user's schedules, by id
+#[derive(Default)]
+pub struct Scheduler {
+    next_id: u32,
+    schedules: BTreeMap<u32, Schedule>,
+    /// Runs of removed schedules that haven't finished yet, by schedule id
+    orphaned_runs: HashMap<u32, u32>,
+}
+
+impl Scheduler {
+    /// Register a schedule from either a cron expression or a one-shot time
+    pub fn add(
+        &mut self,
+        owner: &str,
+        name: Option<String>,
+        command: &str,
+        cron: Option<&str>,
+        at: Option<DateTime<Utc>>,
+    ) -> Result<u32, String> {
+        if command.trim().is_empty() {
+            return Err("command must not be empty".to_string());
+        }
+        // One-shots that have fired only keep their history, so they don't count
+        let active = self.schedules.values().filter(|s| s.owner == owner && !s.is_spent()).count();
+        if active >= SCHEDULES_PER_USER {
+            return Err(format!("at most {} schedules per user", SCHEDULES_PER_USER));
+        }
+
+        let now = Utc::now();
+        let (when, next_run) = match (cron, at) {
+            (Some(expr), None) => {
+                let cron = Cron::new(expr).parse().map_err(|e| format!("invalid cron expression: {}", e))?;
+                let next = cron
+                    .find_next_occurrence(&now, false)
+                    .map_err(|e| format!("cron expression never fires: {}", e))?;
+                (When::Cron(Box::new(cron)), next)
+            }
+            (None, Some(at)) if at > now => (When::Once(at), at),
+            (None, Some(_)) => return Err("`at` is in the past".to_string()),
+            _ => return Err("give exactly one of `cron` or `at`".to_string()),
+        };
+
+        self.next_id += 1;
+        let id = self.next_id;
+        self.schedules.insert(id, Schedule {
+            id,
+            owner: owner.to_string(),
+            name,
+            command: command.to_string(),
+            when,
+            created_at: now,
+            next_run: Some(next_run),
+            running_pid: None,
+            history: VecDeque::new(),
+        });
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Schedule> {
+        self.schedules.get(&id)
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Schedule> {
+        self.schedules.get_mut(&id)
+    }
+
+    /// A schedule, if it belongs to `owner`
+    pub fn owned(&self, id: u32, owner: &str) -> Option<&Schedule> {
+        self.schedules.get(&id).filter(|s| s.owner == owner)
+    }
+
+    pub fn list(&self, owner: &str) -> Vec<&Schedule> {
+        self.schedules.values().filter(|s| s.owner == owner).collect()
+    }
+
+    /// Process ids of runs still in progress, including those of removed schedules
+    pub fn running_pids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.schedules.values().filter_map(|s| s.running_pid).chain(self.orphaned_runs.values().copied())
+    }
+
+    /// Remove a schedule; a run in progress stays tracked until it finishes
+    pub fn remove(&mut self, id: u32, owner: &str) -> Option<Schedule> {
+        self.owned(id, owner)?;
+        let schedule = self.schedules.remove(&id)?;
+        if let Some(pid) = schedule.running_pid {
+            self.orphaned_runs.insert(id, pid);
+        }
+        Some(schedule)
+    }
+
+    /// Ids of schedules due by `now`, moving each on to its next run
+    pub fn take_due(&mut self, now: DateTime<Utc>) -> Vec<u32> {
+        let mut due = Vec::new();
+        for schedule in self.schedules.values_mut() {
+            if schedule.next_run.is_none_or(|t| t > now) {
+                continue;
+            }
+            schedule.next_run = schedule.next_after(now);
+            if schedule.running_pid.is_some() {
+                tracing::info!(schedule_id = schedule.id, "schedule is still running, skipping this run");
+                continue;
+            }
+            due.push(schedule.id);
+        }
+        due
+    }
+
+    /// Add a finished run to a schedule's history
+    pub fn record(&mut self, id: u32, run: ScheduleRun) -> Option<&Schedule> {
+        self.orphaned_runs.remove(&id);
+        let schedule = self.schedules.get_mut(&id)?;
+        schedule.running_pid = None;
+        schedule.history.push_back(run);
+        while schedule.history.len() > HISTORY_KEPT {
+            schedule.history.pop_front();
+        }
+        Some(schedule)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn rejects_bad_cron_and_mixed_times() {
+        let mut scheduler = Scheduler::default();
+        assert!(scheduler.add("alice", None, "true", Some("not a cron"), None).is_err());
+        assert!(scheduler.add("alice", None, "true", Some("* * * * *"), Some(Utc::now() + Duration::hours(1))).is_err());
+        assert!(scheduler.add("alice", None, "true", None, None).is_err());
+        assert!(scheduler.add("alice", None, "true", None, Some(Utc::now() - Duration::hours(1))).is_err());
+        assert!(scheduler.add("alice", None, "  ", Some("* * * * *"), None).is_err());
+    }
+
+    #[test]
+    fn cron_schedules_move_on_to_their_next_run() {
+        let mut scheduler = Scheduler::default();
+        let id = scheduler.add("alice", None, "true", Some("*/5 * * * *"), None).unwrap();
+        let first = scheduler.get(id).unwrap().next_run.unwrap();
+        assert_eq!(first.timestamp() % 300, 0);
+
+        assert!(scheduler.take_due(first - Duration::seconds(1)).is_empty());
+        assert_eq!(scheduler.take_due(first), vec![id]);
+        assert_eq!(scheduler.get(id).unwrap().next_run, Some(first + Duration::minutes(5)));
+    }
+
+    #[test]
+    fn running_schedules_skip_rather_than_overlap() {
+        let mut scheduler = Scheduler::default();
+        let id = scheduler.add("alice", None, "true", Some("* * * * *"), None).unwrap();
+        let first = scheduler.get(id).unwrap().next_run.unwrap();
+        scheduler.get_mut(id).unwrap().running_pid = Some(42);
+
+        assert!(scheduler.take_due(first).is_empty());
+        assert_eq!(scheduler.get(id).unwrap().next_run, Some(first + Duration::minutes(1)));
+    }
+
+    #[test]
+    fn one_shots_fire_once_and_keep_their_history() {
+        let mut scheduler = Scheduler::default();
+        let at = Utc::now() + Duration::hours(1);
+        let id = scheduler.add("alice", None, "true", None, Some(at)).unwrap();
+
+        assert_eq!(scheduler.take_due(at), vec![id]);
+        assert!(scheduler.take_due(at + Duration::hours(1)).is_empty());
+        scheduler.record(id, ScheduleRun::new(at, RunStatus::Succeeded, ExitReport::exited(0), b"ok", b""));
+
+        let schedule = scheduler.get(id).unwrap();
+        assert!(schedule.is_spent());
+        assert_eq!(schedule.history().count(), 1);
+    }
+
+    #[test]
+    fn spent_one_shots_dont_count_against_the_cap() {
+        let mut scheduler = Scheduler::default();
+        let at = Utc::now() + Duration::hours(1);
+        for _ in 0..SCHEDULES_PER_USER {
+            scheduler.add("alice", None, "true", None, Some(at)).unwrap();
+        }
+        assert!(scheduler.add("alice", None, "true", None, Some(at)).is_err());
+        assert!(scheduler.add("bob", None, "true", None, Some(at)).is_ok());
+
+        scheduler.take_due(at);
+        assert!(scheduler.add("alice", None, "true", None, Some(at + Duration::hours(1))).is_ok());
+    }
+
+    #[test]
+    fn removed_schedules_keep_their_run_tracked() {
+        let mut scheduler = Scheduler::default();
+        let id = scheduler.add("alice", None, "true", Some("* * * * *"), None).unwrap();
+        scheduler.get_mut(id).unwrap().running_pid = Some(42);
+
+        assert!(scheduler.remove(id, "bob").is_none());
+        assert!(scheduler.remove(id, "alice").is_some());
+        assert_eq!(scheduler.running_pids().collect::<Vec<_>>(), vec![42]);
+        scheduler.record(id, ScheduleRun::new(Utc::now(), RunStatus::Failed, ExitReport::exited(1), b"", b""));
+        assert_eq!(scheduler.running_pids().count(), 0);
+    }
+}
@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A shell or interpreter that a session runs its commands through.
+#[derive(Debug, Clone)]
+pub struct Shell {
+    pub name: String,
+    pub program: String,
+    pub args: Vec<String>,
+    pub env: HashMap<String, String>,
+    pub clear_env: bool,
+}
+
+impl Shell {
+    /// Definitions for the shells we know how to drive out of the box.
+    pub fn builtin(name: &str) -> Option<Shell> {
+        let (program, args): (&str, &[&str]) = match name {
+            "bash" => ("bash", &["-c"]),
+            "sh" => ("sh", &["-c"]),
+            "zsh" => ("zsh", &["-c"]),
+            "fish" => ("fish", &["-c"]),
+            "rbash" => ("rbash", &["-c"]),
+            "python3" => ("python3", &["-c"]),
+            "cmd" => ("cmd", &["/C"]),
+            "powershell" => ("powershell", &["-NoProfile", "-Command"]),
+            _ => return None,
+        };
+
+        Some(Shell {
+            name: name.to_string(),
+            program: program.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            env: HashMap::new(),
+            clear_env: false,
+        })
+    }
+
+    /// Build a process that runs `script` through this shell.
+    pub fn command(&self, script: &str) -> Command {
+        let mut cmd = Command::new(&self.program);
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        cmd.envs(&self.env).args(&self.args).arg(script);
+        cmd
+    }
+}
+
+/// How a shell's working directory is followed between commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CwdTracking {
+    /// POSIX-style shell: it reports `$PWD` (and optionally its `pushd`
+    /// stack) on exit, so whatever it actually did is what we keep
+    Reported { dir_stack: bool },
+    /// Shell we can't ask; fall back to interpreting `cd` ourselves
+    Emulated,
+    /// Working directory never changes (interpreters, restricted shells)
+    Fixed,
+}
+
+/// Directory state a shell left behind, as written by the tracking wrapper.
+pub struct CwdReport {
+    pub cwd: PathBuf,
+    pub oldpwd: Option<PathBuf>,
+    pub dir_stack: Vec<PathBuf>,
+}
+
+impl Shell {
+    pub fn cwd_tracking(&self) -> CwdTracking {
+        let program = Path::new(&self.program)
+            .file_stem()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        match program.as_str() {
+            "bash" => CwdTracking::Reported { dir_stack: true },
+            "sh" | "dash" | "zsh" | "ksh" => CwdTracking::Reported { dir_stack: false },
+            // rbash refuses both `cd` and output redirection
+            "rbash" | "python" | "python3" => CwdTracking::Fixed,
+            _ => CwdTracking::Emulated,
+        }
+    }
+
+    /// Wrap `script` so the shell restores the session's `pushd` stack first
+    /// and writes its final `$PWD`, `$OLDPWD` and stack to `state_file` on exit.
+    pub fn wrap_tracked(&self, script: &str, dir_stack: &[PathBuf], state_file: &Path) -> String {
+        let file = quote(&state_file.to_string_lossy());
+        let mut prelude = String::new();
+
+        let report = match self.cwd_tracking() {
+            CwdTracking::Reported { dir_stack: true } => {
+                // `pushd -n` inserts below the top, so push the bottom entry first
+                for dir in dir_stack.iter().rev() {
+                    prelude.push_str(&format!("pushd -n -- {} >/dev/null; ", quote(&dir.to_string_lossy())));
+                }
+                format!("printf '%s\\n%s\\n' \"$PWD\" \"$OLDPWD\" > {file}; dirs -p -l | tail -n +2 >> {file}")
+            }
+            _ => format!("printf '%s\\n%s\\n' \"$PWD\" \"$OLDPWD\" > {file}"),
+        };
+
+        // Keep the user's script on the first line so error line numbers still match
+        format!("{}trap {} EXIT; {}", prelude, quote(&report), script)
+    }
+}
+
+impl CwdReport {
+    /// Read and remove a state file written by `Shell::wrap_tracked`.
+    pub fn take(state_file: &Path) -> Option<CwdReport> {
+        let contents = std::fs::read_to_string(state_file).ok();
+        let _ = std::fs::remove_file(state_file);
+
+        let mut lines = contents?.lines().map(str::to_owned).collect::<Vec<_>>().into_iter();
+        let cwd = lines.next().filter(|l| !l.is_empty())?;
+        let oldpwd = lines.next().filter(|l| !l.is_empty()).map(PathBuf::from);
+
+        Some(CwdReport {
+            cwd: PathBuf::from(cwd),
+            oldpwd,
+            dir_stack: lines.map(PathBuf::from).collect(),
+        })
+    }
+}
+
+/// Single-quote a string for a POSIX shell.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
@@ -1,32 +1,127 @@
-use crate::webSocketNeo::WsConn;
+use crate::api::room_error;
+use crate::config::{ServerConfig, SessionProfile};
+use crate::invite::{self, InviteRole};
 use crate::lobby::Lobby;
+use crate::message::RoomJoin;
+use crate::metrics::METRICS;
+use crate::room::RoomError;
+use crate::shutdown::Draining;
+use crate::webSocketNeo::WsConn;
 use actix::Addr;
 use actix_web::{get, web, Error, HttpResponse, HttpRequest};
 use actix_web_actors::ws;
+use serde::Deserialize;
 use uuid::Uuid;
 
+/// Optional query parameters on the WebSocket handshake, also taken when
+/// opening an HTTP session
+#[derive(Deserialize)]
+pub struct ConnectParams {
+    pub shell: Option<String>,
+    pub token: Option<String>,
+    /// Share one shell with everyone else in the room
+    #[serde(default)]
+    pub shared: bool,
+    /// For rooms created with a password or as invite-only
+    pub password: Option<String>,
+    /// A room's invite code, or a signed invite link, which works without `token`
+    pub invite: Option<String>,
+}
+
+/// A caller let into a room, ready to start a session
+pub struct Admitted {
+    pub room: Uuid,
+    pub profile: SessionProfile,
+    pub lobby: Addr<Lobby>,
+}
+
+/// Check a joining client's credentials, shell and room, whichever transport it uses.
+/// `endpoint` labels auth failures in the metrics.
+pub async fn admit(
+    req: &HttpRequest,
+    group_id: String,
+    params: &ConnectParams,
+    config: &ServerConfig,
+    endpoint: &str,
+) -> Result<Admitted, Error> {
+    let draining = req.app_data::<web::Data<Draining>>().is_some_and(|d| d.is_draining());
+    if draining {
+        return Err(actix_web::error::ErrorServiceUnavailable("server is shutting down"));
+    }
+
+    // A signed invite is a credential on its own, checked once the room is known
+    let invited = params.token.is_none() && params.invite.as_deref().is_some_and(invite::is_signed);
+    let user = match config.authenticate(params.token.as_deref()) {
+        Ok(user) => user,
+        Err(_) if invited => None,
+        Err(e) => {
+            METRICS.auth_failed(endpoint);
+            return Err(actix_web::error::ErrorUnauthorized(e));
+        }
+    };
+
+    // Handshake choice wins over the user's profile, which wins over the server default
+    let shell_name = params
+        .shell
+        .clone()
+        .or_else(|| user.and_then(|u| u.shell.clone()))
+        .unwrap_or_else(|| config.default_shell.clone());
+    let shell = config.shell(&shell_name).ok_or_else(|| {
+        actix_web::error::ErrorBadRequest(format!("shell '{}' is not allowed", shell_name))
+    })?;
+
+    let srv: Addr<Lobby> = req
+        .app_data::<web::Data<Addr<Lobby>>>()
+        .map(|d| d.get_ref().clone())
+        .ok_or_else(|| actix_web::error::ErrorInternalServerError("Lobby not found"))?;
+
+    let admission = srv
+        .send(RoomJoin {
+            room: group_id,
+            password: params.password.clone(),
+            invite: params.invite.clone(),
+        })
+        .await
+        .map_err(actix_web::error::ErrorInternalServerError)?
+        .map_err(|e| {
+            if matches!(e, RoomError::Forbidden(_)) {
+                METRICS.auth_failed(endpoint);
+            }
+            room_error(e)
+        })?;
+    let group_uuid: Uuid = admission.room;
+
+    let mut profile = config.session_profile(user, shell);
+    // Forwarding headers are whatever the client says, unless a proxy we trust set them
+    let peer = req.peer_addr();
+    profile.remote_addr = peer.map(|p| p.to_string());
+    if peer.is_some_and(|p| config.trusted_proxies.contains(&p.ip())) {
+        profile.forwarded_for = req.connection_info().realip_remote_addr().map(str::to_string);
+    }
+    if let Some(claims) = admission.invite {
+        profile.read_only = claims.role == InviteRole::Viewer;
+        profile.invite = Some(claims.id);
+        profile.expires_at = claims.expires_at();
+        tracing::info!(invite_id = %claims.id, role = ?claims.role, room = %group_uuid, "invite connecting");
+    }
+    if let Some(user) = &profile.user {
+        tracing::info!(user, role = %profile.role, room = %group_uuid, shell = %profile.shell.name, "user connecting");
+    }
+
+    Ok(Admitted { room: group_uuid, profile, lobby: srv })
+}
+
+/// Join an existing room, named by its id or slug
 #[get("/ws/{group_id}")]
 pub async fn start_connection(
     req: HttpRequest,
     body: web::Payload,
     path: web::Path<String>,
+    params: web::Query<ConnectParams>,
+    config: web::Data<ServerConfig>,
 ) -> Result<HttpResponse, Error> {
-    let group_id = path.into_inner();
-    
-    // Try to parse as UUID, or generate a new one if invalid
-    let group_uuid: Uuid = group_id
-        .parse()
-        .unwrap_or_else(|_| {
-            println!("Invalid UUID '{}', generating new one", group_id);
-            Uuid::new_v4()
-        });
-
-    let srv: Addr<crate::lobby::Lobby> = req
-        .app_data::<web::Data<Addr<crate::lobby::Lobby>>>()
-        .map(|d| d.get_ref().clone())
-        .ok_or_else(|| actix_web::error::ErrorInternalServerError("Lobby not found"))?;
-
-    let ws = WsConn::new(group_uuid, srv);
+    let admitted = admit(&req, path.into_inner(), &params, &config, "ws").await?;
+    let ws = WsConn::new(admitted.room, admitted.lobby, admitted.profile, params.shared);
     let resp = ws::start(ws, &req, body)?;
     Ok(resp)
 }
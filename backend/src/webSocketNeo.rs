@@ -2,10 +2,13 @@ use actix::{fut, Actor, ActorContext, ActorFutureExt, Addr, Running, StreamHandl
 use actix_web_actors::ws;
 use actix_web_actors::ws::Message::Text;
 use std::time::{Duration, Instant};
+use tracing::Span;
 use uuid::Uuid;
 
 use crate::lobby::Lobby;
-use crate::message::{Connect, Disconnect, ClientActorMessage, WsMessage};
+use crate::message::{CloseSession, Connect, Disconnect, ClientActorMessage, WsBinaryMessage, WsMessage};
+use crate::config::SessionProfile;
+use crate::metrics::METRICS;
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
@@ -17,16 +20,24 @@ pub struct WsConn {
     hb: Instant,
     id: Uuid,
     last_ping: Instant, // Track when we last sent a ping
+    profile: SessionProfile,
+    shared: bool,
+    /// Carries the session's id, user and room into everything logged for it
+    span: Span,
 }
 
 impl WsConn {
-    pub fn new(room: Uuid, lobby: Addr<Lobby>) -> WsConn {
+    pub fn new(room: Uuid, lobby: Addr<Lobby>, profile: SessionProfile, shared: bool) -> WsConn {
+        let id = Uuid::new_v4();
         WsConn {
-            id: Uuid::new_v4(),
+            span: tracing::info_span!("session", session_id = %id, user = profile.user.as_deref(), room = %room),
+            id,
             room,
             hb: Instant::now(),
             lobby_addr: lobby,
             last_ping: Instant::now(),
+            profile,
+            shared,
         }
     }
 }
@@ -35,15 +46,21 @@ impl Actor for WsConn {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let _span = self.span.clone().entered();
+        tracing::debug!("session started");
         self.hb(ctx);
         self.start_ping_task(ctx); // Start the ping task
 
         let addr = ctx.address();
         self.lobby_addr
             .send(Connect {
-                addr: addr.recipient(),
+                addr: addr.clone().recipient(),
+                binary_addr: addr.clone().recipient(),
+                close_addr: addr.recipient(),
                 lobby_id: self.room,
                 self_id: self.id,
+                profile: self.profile.clone(),
+                shared: self.shared,
             })
             .into_actor(self)
             .then(|res, _, ctx| {
@@ -57,6 +74,8 @@ impl Actor for WsConn {
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        let _span = self.span.enter();
+        tracing::debug!("session stopping");
         self.lobby_addr.do_send(Disconnect {
             self_id: self.id,
             lobby_id: self.room,
@@ -68,8 +87,10 @@ impl Actor for WsConn {
 impl WsConn {
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            let _span = act.span.enter();
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                println!("disconnecting due to heartbeat");
+                tracing::info!("disconnecting due to missed heartbeats");
+                METRICS.heartbeat_timeouts.inc();
                 act.lobby_addr.do_send(Disconnect {
                     self_id: act.id,
                     lobby_id: act.room,
@@ -84,8 +105,9 @@ impl WsConn {
     // New ping task to send periodic pings to keep connection alive
     fn start_ping_task(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(PING_INTERVAL, |act, ctx| {
-            println!("Sending keepalive ping to client {}", act.id);
-            
+            let _span = act.span.enter();
+            tracing::trace!("sending keepalive ping");
+
             // Send a JSON ping message that the client can recognize
             let ping_message = serde_json::json!({
                 "type": "ping",
@@ -105,15 +127,16 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
         msg: Result<ws::Message, ws::ProtocolError>,
         ctx: &mut Self::Context,
     ) {
+        let _span = self.span.clone().entered();
         match msg {
             Ok(ws::Message::Ping(msg)) => {
                 self.hb = Instant::now();
-                println!("Received ping from client, sending pong");
+                tracing::trace!("received ping, sending pong");
                 ctx.pong(&msg);
             }
             Ok(ws::Message::Pong(_)) => {
                 self.hb = Instant::now();
-                println!("Received pong from client");
+                tracing::trace!("received pong");
             }
             Ok(ws::Message::Binary(bin)) => ctx.binary(bin),
             Ok(ws::Message::Close(reason)) => {
@@ -130,12 +153,11 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
                     if let Some(msg_type) = parsed.get("type").and_then(|t| t.as_str()) {
                         match msg_type {
                             "pong" => {
-                                println!("Received JSON pong from client {}", self.id);
+                                tracing::trace!("received JSON pong");
                                 self.hb = Instant::now();
-                                return;
                             }
                             "ping" => {
-                                println!("Received JSON ping from client {}, sending pong", self.id);
+                                tracing::trace!("received JSON ping, sending pong");
                                 let pong_message = serde_json::json!({
                                     "type": "pong",
                                     "timestamp": chrono::Utc::now().timestamp(),
@@ -143,7 +165,6 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
                                 });
                                 ctx.text(pong_message.to_string());
                                 self.hb = Instant::now();
-                                return;
                             }
                             _ => {
                                 // Handle other message types normally
@@ -172,7 +193,8 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsConn {
                 }
             }
             Err(e) => {
-                eprintln!("WebSocket protocol error: {:?}", e);
+                tracing::warn!(error = ?e, "WebSocket protocol error");
+                METRICS.ws_protocol_errors.inc();
                 ctx.stop();
             }
         }
@@ -183,6 +205,28 @@ impl Handler<WsMessage> for WsConn {
     type Result = ();
 
     fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
+        METRICS.ws_sent_bytes.with_label_values(&["text"]).inc_by(msg.message.len() as u64);
         ctx.text(msg.message);
     }
 }
+
+impl Handler<CloseSession> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: CloseSession, ctx: &mut Self::Context) {
+        ctx.close(Some(ws::CloseReason {
+            code: msg.code,
+            description: Some(msg.reason),
+        }));
+        ctx.stop();
+    }
+}
+
+impl Handler<WsBinaryMessage> for WsConn {
+    type Result = ();
+
+    fn handle(&mut self, msg: WsBinaryMessage, ctx: &mut Self::Context) {
+        METRICS.ws_sent_bytes.with_label_values(&["binary"]).inc_by(msg.data.len() as u64);
+        ctx.binary(msg.data);
+    }
+}
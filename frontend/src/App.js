@@ -6,20 +6,11 @@ import AutoComplete from './components/AutoComplete';
 import './App.css';
 
 function App() {
-  // Generate a valid UUID for the room
-  const generateUUID = () => {
-    return 'xxxxxxxx-xxxx-4xxx-yxxx-xxxxxxxxxxxx'.replace(/[xy]/g, function(c) {
-      const r = Math.random() * 16 | 0;
-      const v = c === 'x' ? r : ((r & 0x3) | 0x8);
-      return v.toString(16);
-    });
-  };
-
   const [command, setCommand] = useState('');
   const [output, setOutput] = useState([]);
   const [isConnected, setIsConnected] = useState(false);
   const [isConnecting, setIsConnecting] = useState(false);
-  const [connectionUrl, setConnectionUrl] = useState(`ws://localhost:8080/ws/${generateUUID()}`); // Added room ID
+  const [connectionUrl, setConnectionUrl] = useState('ws://localhost:8080/ws/new'); // "new" creates a room on connect
   const [commandHistory, setCommandHistory] = useState([]);
   const [historyIndex, setHistoryIndex] = useState(-1);
   const [currentDirectory, setCurrentDirectory] = useState('/');
@@ -63,8 +54,9 @@ function App() {
         const newOutput = {
           id: generateId(),
           command: message.payload.command,
-          stdout: message.payload.stdout || '',
-          stderr: message.payload.stderr || '',
+          // The server sends output untouched; trimming is only for display
+          stdout: (message.payload.stdout || '').trim(),
+          stderr: (message.payload.stderr || '').trim(),
           exitCode: message.payload.exitCode || 0,
           timestamp: new Date().toLocaleTimeString(),
           currentDirectory: message.payload.currentDirectory
@@ -465,12 +457,40 @@ function App() {
     }
   };
 
-  const handleConnect = () => {
+  // Rooms must exist before joining them, so "/ws/new" asks the server for one
+  const createRoom = async (url) => {
+    const parsed = new URL(url);
+    const apiUrl = `${parsed.protocol.replace(/^ws/, 'http')}//${parsed.host}/api/rooms${parsed.search}`;
+    const response = await fetch(apiUrl, {
+      method: 'POST',
+      headers: { 'Content-Type': 'application/json' },
+      body: JSON.stringify({})
+    });
+    if (!response.ok) {
+      throw new Error(`Could not create a room: ${response.status} ${await response.text()}`);
+    }
+    const room = await response.json();
+    parsed.pathname = `/ws/${room.slug}`;
+    return parsed.toString();
+  };
+
+  const handleConnect = async () => {
     if (isConnected) {
       WebSocketService.disconnect();
     } else {
+      let url = connectionUrl;
+      if (/\/ws\/new\/?$/.test(new URL(url).pathname)) {
+        try {
+          url = await createRoom(url);
+          setConnectionUrl(url);
+        } catch (error) {
+          console.error(error);
+          addSystemMessage(error.message);
+          return;
+        }
+      }
       WebSocketService.resetReconnection();
-      WebSocketService.connect(connectionUrl);
+      WebSocketService.connect(url);
     }
   };
 
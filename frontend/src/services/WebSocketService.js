@@ -1,3 +1,12 @@
+// Close reasons sent when the server deliberately ends a session
+const SERVER_CLOSE_REASONS = [
+  'session_expired',
+  'disconnected by an admin',
+  'invite revoked',
+  'room closed',
+  'room expired'
+];
+
 class WebSocketService {
   constructor() {
     this.ws = null;
@@ -367,6 +376,11 @@ class WebSocketService {
           wasImmediateDisconnect
         });
         
+        // The server ended the session on purpose; coming straight back would defeat it
+        if (SERVER_CLOSE_REASONS.includes(event.reason)) {
+          this.shouldReconnect = false;
+        }
+
         // Enhanced reconnection logic with circuit breaker
         if (this.shouldReconnect && this.canAttemptConnection()) {
           // Switch to aggressive retry for firewall issues